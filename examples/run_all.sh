@@ -1,8 +1,17 @@
 #!/bin/sh
 
+status=0
+
+# Examples with a file under examples/expected must print exactly its contents
 run_example() {
   echo "[Example] Running $1"
-  target/debug/quick < examples/$1.qk
+  if [ -f examples/expected/$1.txt ]; then
+    out=$(target/debug/quick examples/$1.qk)
+    echo "$out"
+    echo "$out" | diff -u examples/expected/$1.txt - || status=1
+  else
+    target/debug/quick examples/$1.qk
+  fi
 }
 
 run_example math
@@ -11,5 +20,20 @@ run_example i-fib
 run_example r-fib
 run_example arrays
 run_example quantum
+run_example gates
+run_example arith
 run_example teleport
 run_example dft
+run_example grover
+run_example prelude
+run_example modules
+run_example consts
+run_example loops
+run_example reversible
+run_example qif
+run_example views
+run_example maps
+run_example structs
+run_example tuples
+
+exit $status
@@ -19,13 +19,16 @@ use util::ops::*;
 use util::string_table::StringToken;
 
 use backend::runtime::IRT_TABLE;
+use backend::runtime::structure::StructLayout;
 
+use std::rc::Rc;
 use std::vec::Vec;
 
 #[derive(Debug)]
 pub struct Program {
     pub instructions: Vec<Bytecode>,
-    pub call_table: Vec<FunctionEntry>
+    pub call_table: Vec<FunctionEntry>,
+    pub debug: Vec<DebugEntry>,
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -58,12 +61,23 @@ impl FunctionToken {
 
 #[derive(Debug)]
 pub struct FunctionEntry {
+    pub name: Option<StringToken>,
     pub addr: usize,
     pub arity: usize,
     pub locals: usize,
     pub ioffset: Option<usize>
 }
 
+// Marks the start of a statement (or, with no source position, the entry of
+// a function) along with the local variables visible at that point, as pairs
+// of name and frame slot. Entries are sorted by instruction address.
+#[derive(Debug)]
+pub struct DebugEntry {
+    pub pc: usize,
+    pub pos: Option<usize>,
+    pub locals: Vec<(StringToken, usize)>,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Call {
     Regular,
@@ -77,16 +91,27 @@ pub enum Bytecode {
     Int(i64),
     Float(f64),
     Bool(bool),
+    String(StringToken),
     Func(FunctionToken),
     Array(usize),
+    Tuple(usize),
+    Unpack(Call, usize),
+    Map(usize),
+    Struct(Rc<StructLayout>),
     Op2(Call, BinOp),
     Op1(Call, UnOp),
     Call(Call, usize),
     Discard,
+    Save(usize),
+    Stash,
+    Unstash,
+    Control,
+    Uncontrol,
     Return,
     PutLocal(usize),
     GetLocal(usize),
     Jump(isize),
     Branch(isize),
+    BranchFalse(isize),
     Print(StringToken, usize),
 }
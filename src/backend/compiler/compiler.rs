@@ -16,27 +16,88 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use frontend::ast::*;
-use backend::bytecode::Program;
+use frontend::modules::Module;
+use backend::bytecode::{FunctionToken, Program};
+use backend::compiler::constant::{self, Constant};
 use backend::compiler::function::Functions;
 use backend::compiler::environment::LocalEnvironment;
+use backend::runtime::IRT_STRINGS;
 use util::ops::*;
-use util::string_table;
+use util::string_table::{self, StringToken};
 
 use std::borrow::Borrow;
+use std::cmp;
+use std::collections::LinkedList;
 use std::fmt::Write;
 
-pub fn compile(ast: &Ast) -> Result<Program, String> {
+// Builtins which cannot be controlled on a qubit, and so may not be called in
+// the body of a qif.
+const UNCONTROLLABLE: &'static [&'static str] = &["measure", "cphase"];
+
+// Compiles a program along with the prelude and the modules it imports, as
+// returned by modules::load. The prelude comes first, and each module gets a
+// scope of its own, so that a module may define functions in place of the
+// prelude's without its names colliding with any other module's. Constants
+// are only ever declared at the top level of a file, and are visible to all
+// of its functions, wherever in the file they are declared.
+pub fn compile(prelude: &Ast, modules: &[Module], optimize: bool) -> Result<Program, String> {
     let mut env = LocalEnvironment::new();
     let mut fns = Functions::new();
-    for stmt in ast.iter() {
+    for stmt in prelude.iter() {
         compile_stmt(stmt, &mut fns, &mut env)?;
     }
+    fns.forget_statements();
+    let (program, imported) = modules.split_last().unwrap();
+    for m in imported.iter() {
+        fns.begin_module(&m.imports);
+        define_top_level(&m.ast, &mut fns).map_err(|err| format!("In module '{}': {}", m.name, err))?;
+        for stmt in m.ast.iter() {
+            compile_module_stmt(stmt, &mut fns, &mut env).map_err(|err| format!("In module '{}': {}", m.name, err))?;
+        }
+        fns.end_module();
+    }
+    fns.begin_module(&program.imports);
+    define_top_level(&program.ast, &mut fns)?;
+    for stmt in program.ast.iter() {
+        match *stmt.unlocated() {
+            Stmt::Import(..) | Stmt::DefConst(..) | Stmt::DefStruct(..) => (),
+            _ => compile_stmt(stmt, &mut fns, &mut env)?,
+        }
+    }
     {
         let top_func = fns.current();
         top_func.return_();
         top_func.set_locals(env.locals());
     }
-    Ok(fns.to_program())
+    Ok(fns.to_program(optimize))
+}
+
+// Defines the constants and structs declared at the top level of a file, in
+// the order they are declared, before any of its functions are compiled.
+fn define_top_level(ast: &Ast, fns: &mut Functions) -> Result<(), String> {
+    for stmt in ast.iter() {
+        match *stmt.unlocated() {
+            Stmt::DefConst(name, ref e) => {
+                let value = constant::eval(e, fns)?;
+                fns.define_const(name, value)?;
+            },
+            Stmt::DefStruct(name, ref fields) => fns.define_struct(name, fields)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Modules only define functions and constants for others to use, as anything
+// else at their top level would have nowhere to run. Structs are left to the
+// program, as there is no way to name one belonging to a module.
+fn compile_module_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *stmt.unlocated() {
+        Stmt::Import(..) | Stmt::DefConst(..) => Ok(()),
+        Stmt::DefFunc(..) | Stmt::DefRFunc(..) => compile_stmt(stmt, fns, env),
+        Stmt::DefStruct(..) => return_error!("Structs may not be declared in a module"),
+        _ => return_error!("Only functions and constants may be defined at the top level of a module"),
+    }
 }
 
 fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
@@ -44,9 +105,9 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
         Stmt::DefFunc(name, ref params, ref b) => {
             fns.push_func(name, params.len())?;
             let mut new_env = LocalEnvironment::new();
-            for p in params.iter() {
-                new_env.add_id(*p)?;
-            }
+            declare_params(params, &mut new_env)?;
+            fns.current().mark(None, new_env.names());
+            unpack_params(params, fns, &new_env);
             compile_expr(b, fns, &mut new_env)?;
             {
                 let new_func = fns.current();
@@ -59,12 +120,17 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
             fns.push_func(name, params.len())?;
             {
                 let mut new_env = LocalEnvironment::new();
-                for p in params.iter() {
-                    new_env.add_id(*p)?;
-                }
+                declare_params(params, &mut new_env)?;
+                fns.current().mark(None, new_env.names());
+                unpack_params(params, fns, &new_env);
                 compile_rev_expr(b, fns, &mut new_env)?;
+                let ft = fns.lookup(name).unwrap();
                 {
+                    // Leave the function and its arguments on aux, where an
+                    // inverse call expects to find them
                     let new_func = fns.current();
+                    new_func.func(ft);
+                    new_func.save(params.len());
                     new_func.return_();
                     new_func.set_locals(new_env.locals());
                 }
@@ -72,24 +138,24 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
             fns.current().set_inverse();
             {
                 let mut new_env = LocalEnvironment::new();
-                for p in params.iter() {
-                    new_env.add_id(*p)?;
-                }
+                declare_params(params, &mut new_env)?;
+                unpack_params(params, fns, &new_env);
                 compile_inv_expr(b, fns, &mut new_env)?;
                 {
                     let new_func = fns.current();
                     new_func.return_();
-                    assert_eq!(new_env.locals(), new_func.locals());
+                    // Going backwards, a scope's variables are all declared
+                    // on the way in, which may take more slots
+                    let locals = cmp::max(new_env.locals(), new_func.locals());
+                    new_func.set_locals(locals);
                 }
             }
             fns.pop_func();
         },
-        Stmt::DefVar(name, ref e) => {
+        Stmt::DefVar(ref p, ref e) => {
             compile_expr(e, fns, env)?;
-            let offset = env.add_id(name)?;
-            let func = fns.current();
-            func.put_local(offset);
-            func.discard();
+            declare_pattern(p, env)?;
+            put_pattern(p, false, fns, env);
         },
         Stmt::Block(ref stmts) => {
             env.push_scope();
@@ -98,27 +164,15 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
             }
             env.pop_scope();
         },
-        Stmt::While(ref p, ref b) => {
-            let start_loop;
-            let end_loop;
-            {
-                let func = fns.current();
-                start_loop = func.label();
-                end_loop = func.label();
-                func.bind(start_loop);
-            }
-            compile_expr(p, fns, env)?;
-            {
-                let func = fns.current();
-                func.op1(UnOp::Not);
-                func.branch(end_loop);
-            }
-            compile_stmt(b.borrow(), fns, env)?;
-            {
-                let func = fns.current();
-                func.jump(start_loop);
-                func.bind(end_loop);
-            }
+        Stmt::While(..) | Stmt::ForEach(..) | Stmt::ForLoop(..) => compile_loop(stmt, None, fns, env)?,
+        Stmt::Labeled(label, ref s) => compile_loop(s, Some(label), fns, env)?,
+        Stmt::Break(label) => {
+            let target = env.find_exit("break", label)?;
+            fns.current().jump(target);
+        },
+        Stmt::Continue(label) => {
+            let target = env.find_exit("continue", label)?;
+            fns.current().jump(target);
         },
         Stmt::If(ref p, ref t, ref e) => {
             let other;
@@ -152,17 +206,110 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
                 func.bind(done);
             }
         },
-        Stmt::ForEach(id, ref e, ref b) => {
-            compile_expr(e, fns, env)?;
+        Stmt::With(id, ref p, ref b) => {
+            compile_rev_expr(p, fns, env)?;
             env.push_scope();
             let id = env.add_id(id)?;
+            {
+                let func = fns.current();
+                func.put_local(id);
+                func.discard();
+            }
+            env.push_barrier("a with block");
+            compile_stmt(b.borrow(), fns, env)?;
+            env.pop_exit();
+            fns.current().get_local(id);
+            env.pop_scope();
+            compile_inv_expr(p, fns, env)?;
+        },
+        Stmt::Ctrl(ref c, ref b) => {
+            compile_expr(c, fns, env)?;
+            fns.current().control();
+            env.push_barrier("a ctrl block");
+            compile_stmt(b.borrow(), fns, env)?;
+            env.pop_exit();
+            fns.current().uncontrol();
+        },
+        Stmt::QIf(ref c, ref b) => {
+            compile_expr(c, fns, env)?;
+            fns.current().control();
+            env.push_barrier("a qif block");
+            env.enter_qif();
+            compile_stmt(b.borrow(), fns, env)?;
+            env.leave_qif();
+            env.pop_exit();
+            fns.current().uncontrol();
+        },
+        Stmt::Return(ref e) => {
+            compile_expr(e, fns, env)?;
+            fns.current().return_();
+        },
+        Stmt::Expr(ref e) => {
+            compile_expr(e, fns, env)?;
+            fns.current().discard();
+        },
+        Stmt::Print(lit, ref args) => {
+            if env.in_qif() {
+                return_error!("Print statements may not be used inside a qif block");
+            }
+            for a in args.iter() {
+                compile_expr(a, fns, env)?;
+            }
+            fns.current().print(lit, args.len());
+        },
+        Stmt::Located(pos, ref s) => {
+            fns.current().mark(Some(pos), env.names());
+            compile_stmt(s, fns, env)?;
+        },
+        Stmt::Import(..) => return_error!("Modules may only be imported at the top level of a file"),
+        Stmt::DefConst(..) => return_error!("Constants may only be declared at the top level of a file"),
+        Stmt::DefStruct(..) => return_error!("Structs may only be declared at the top level of a file"),
+    }
+    Ok(())
+}
+
+// Compiles a loop, which break and continue statements within it, by label or
+// otherwise, may jump out of.
+fn compile_loop(stmt: &Stmt, label: Option<StringToken>, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *stmt {
+        Stmt::While(ref p, ref b) => {
+            let start_loop;
+            let end_loop;
+            {
+                let func = fns.current();
+                start_loop = func.label();
+                end_loop = func.label();
+                func.bind(start_loop);
+            }
+            env.push_loop(label, end_loop, start_loop);
+            compile_expr(p, fns, env)?;
+            {
+                let func = fns.current();
+                func.op1(UnOp::Not);
+                func.branch(end_loop);
+            }
+            compile_stmt(b.borrow(), fns, env)?;
+            env.pop_exit();
+            {
+                let func = fns.current();
+                func.jump(start_loop);
+                func.bind(end_loop);
+            }
+        },
+        Stmt::ForEach(ref p, ref e, ref b) => {
+            compile_expr(e, fns, env)?;
+            builtin_call!(fns, iter, 1);
+            env.push_scope();
+            declare_pattern(p, env)?;
             let counter = env.add_tmp();
             let array = env.add_tmp();
             let start_loop;
+            let next;
             let end_loop;
             {
                 let func = fns.current();
                 start_loop = func.label();
+                next = func.label();
                 end_loop = func.label();
 
                 func.put_local(array);
@@ -183,14 +330,13 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
                 func.put_local(counter);
             }
             builtin_call!(fns, get, 2);   
-            {
-                let func = fns.current();
-                func.put_local(id);
-                func.discard();
-            }
+            put_pattern(p, false, fns, env);
+            env.push_loop(label, end_loop, next);
             compile_stmt(b.borrow(), fns, env)?;
+            env.pop_exit();
             {
                 let func = fns.current();
+                func.bind(next);
                 func.get_local(counter);
                 func.int(0);
                 func.op2(BinOp::Gt);
@@ -205,10 +351,12 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
             let id = env.add_id(id)?;
             let end = env.add_tmp();
             let start_loop;
+            let next;
             let end_loop;
             {
                 let func = fns.current();
                 start_loop = func.label();
+                next = func.label();
                 end_loop = func.label();
 
                 func.put_local(id);
@@ -221,9 +369,12 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
                 func.branch(end_loop);
                 func.bind(start_loop); 
             }
+            env.push_loop(label, end_loop, next);
             compile_stmt(b.borrow(), fns, env)?;
+            env.pop_exit();
             {
                 let func = fns.current();
+                func.bind(next);
                 func.get_local(id);
                 func.int(1);
                 func.op2(BinOp::Add);
@@ -237,48 +388,26 @@ fn compile_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) ->
             }
             env.pop_scope();
         },
-        Stmt::With(id, ref p, ref b) => {
-            compile_rev_expr(p, fns, env)?;
-            env.push_scope();
-            let id = env.add_id(id)?;
-            {
-                let func = fns.current();
-                func.put_local(id);
-                func.discard();
-            }
-            compile_stmt(b.borrow(), fns, env)?;
-            fns.current().get_local(id);
-            env.pop_scope();
-            compile_inv_expr(p, fns, env)?;
-        },
-        Stmt::Return(ref e) => {
-            compile_expr(e, fns, env)?;
-            fns.current().return_();
-        },
-        Stmt::Expr(ref e) => {
-            compile_expr(e, fns, env)?;
-            fns.current().discard();
-        },
-        Stmt::Print(lit, ref args) => {
-            for a in args.iter() {
-                compile_expr(a, fns, env)?;
-            }
-            fns.current().print(lit, args.len());
-        }
+        _ => return_error!("Only loops may be labelled"),
     }
     Ok(())
 }
 
 fn compile_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
     match *expr {
+        Expr::Null => fns.current().null(),
         Expr::Int(i) => fns.current().int(i),
         Expr::Float(f) => fns.current().float(f),
         Expr::Bool(b) => fns.current().bool(b),
+        Expr::String(s) => fns.current().string(s),
         Expr::Ref(id) => match env.find(id) {
             Some(offset) => fns.current().get_local(offset),
-            None => match fns.lookup(id) {
-                Some(ft) => fns.current().func(ft),
-                None => return_error!("Identifier '{}' is not defined", string_table::get(id)),
+            None => match fns.lookup_const(id) {
+                Some(c) => c.emit(fns.current()),
+                None => match fns.lookup(id) {
+                    Some(ft) => fns.current().func(ft),
+                    None => return_error!("Identifier '{}' is not defined", string_table::get(id)),
+                },
             },
         },
         Expr::If(ref p, ref t, ref e) => {
@@ -307,11 +436,15 @@ fn compile_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) ->
             }
         },
         Expr::Block(ref stmts, ref e) => {
+            // What the enclosing expression has computed so far is still on
+            // the stack, and jumping out would leave it there
             env.push_scope();
+            env.push_barrier("an expression");
             for s in stmts.iter() {
                 compile_stmt(s, fns, env)?;
             }
             compile_expr(e.borrow(), fns, env)?;
+            env.pop_exit();
             env.pop_scope();
         },
         Expr::Move(id) => match env.find(id) {
@@ -325,6 +458,7 @@ fn compile_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) ->
             None => return_error!("Identifier '{}' is not defined", string_table::get(id)),
         },
         Expr::Call(ref f, ref args) => {
+            check_controllable(f, fns, env)?;
             for a in args.iter() {
                 compile_expr(a, fns, env)?;
             }
@@ -345,6 +479,41 @@ fn compile_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) ->
             let func = fns.current();
             func.array(args.len());
         },
+        Expr::Tuple(ref args) => {
+            for a in args.iter() {
+                compile_expr(a, fns, env)?;
+            }
+            fns.current().tuple(args.len());
+        },
+        Expr::Map(ref entries) => {
+            for &(ref k, ref v) in entries.iter() {
+                compile_expr(k, fns, env)?;
+                compile_expr(v, fns, env)?;
+            }
+            fns.current().map(entries.len());
+        },
+        Expr::Struct(name, ref fields) => {
+            let layout = match fns.lookup_struct(name) {
+                Some(layout) => layout,
+                None => return_error!("Struct '{}' is not defined", string_table::get(name)),
+            };
+            for &(f, _) in fields.iter() {
+                if !layout.fields.contains(&f) {
+                    return_error!("Struct '{}' has no field '{}'", string_table::get(name), string_table::get(f));
+                }
+                if fields.iter().filter(|&&(g, _)| g == f).count() > 1 {
+                    return_error!("Field '{}' of struct '{}' is given more than once", string_table::get(f), string_table::get(name));
+                }
+            }
+            // Fields are evaluated in the order the struct declares them
+            for &f in layout.fields.iter() {
+                match fields.iter().find(|&&(g, _)| g == f) {
+                    Some(&(_, ref e)) => compile_expr(e, fns, env)?,
+                    None => return_error!("Field '{}' of struct '{}' is not given a value", string_table::get(f), string_table::get(name)),
+                }
+            }
+            fns.current().struct_(layout);
+        },
         Expr::UnOp(op, ref e) => {
             compile_expr(e.borrow(), fns, env)?;
             fns.current().op1(op);
@@ -357,106 +526,823 @@ fn compile_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) ->
         Expr::Cat(ref e1, ref e2) => builtin_call!(fns, env, cat, 2, e1, e2),
         Expr::Get(ref e1, ref e2) => builtin_call!(fns, env, get, 2, e1, e2),
         Expr::Put(ref e1, ref e2, ref e3) => builtin_call!(fns, env, put, 3, e1, e2, e3),
-        Expr::Slice(ref e1, ref e2, ref e3) => builtin_call!(fns, env, slice, 3, e1, e2, e3),
+        Expr::Slice(ref e1, ref e2, ref e3, ref e4) => builtin_call!(fns, env, slice, 4, e1, e2, e3, e4),
         Expr::Len(ref e) => builtin_call!(fns, env, len, 1, e),
         Expr::QAlloc(ref n, ref i) => builtin_call!(fns, env, qalloc, 2, n, i),
         Expr::Invoke(ref f) => {
+            check_controllable(f, fns, env)?;
             compile_expr(f.borrow(), fns, env)?;
             fns.current().call(0);
         },
         Expr::Apply(ref f, ref a) => {
+            check_controllable(f, fns, env)?;
             compile_expr(a.borrow(), fns, env)?;
             compile_expr(f.borrow(), fns, env)?;
             fns.current().call(1);
         },
-    }
-    Ok(())
-}
-
-fn compile_rev_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
-    match *expr {
-        Expr::Int(i) => fns.current().int(i),
-        Expr::Bool(b) => fns.current().bool(b),
-        Expr::Ref(id) => match env.find(id) {
-            Some(offset) => fns.current().get_local(offset),
-            None => match fns.lookup(id) {
-                Some(ft) => fns.current().func(ft),
-                None => return_error!("Identifier '{}' is not defined", string_table::get(id)),
-            },
-        },
-        Expr::Call(ref f, ref args) => {
-            for a in args.iter() {
-                compile_rev_expr(a, fns, env)?;
+        Expr::Adjoint(ref f) => {
+            if let Some((name, ft)) = named_func(f, fns, env)? {
+                if !fns.is_reversible(ft) {
+                    return_error!("Function '{}' is not reversible", string_table::get(name));
+                }
             }
-            compile_rev_expr(f.borrow(), fns, env)?;
-            fns.current().rcall(args.len());
-        },
-        Expr::UnOp(op, ref e) => {
-            compile_rev_expr(e.borrow(), fns, env)?;
-            fns.current().rop1(op);
-        },
-        Expr::BinOp(ref e1, op, ref e2) => {
-            compile_rev_expr(e1.borrow(), fns, env)?;
-            compile_rev_expr(e2.borrow(), fns, env)?;
-            fns.current().rop2(op);
+            builtin_call!(fns, env, inverse, 1, f);
         },
-        Expr::Cat(ref e1, ref e2) => builtin_rcall!(fns, env, cat, 2, e1, e2),
-        Expr::Get(ref e1, ref e2) => builtin_rcall!(fns, env, get, 2, e1, e2),
-        Expr::Put(ref e1, ref e2, ref e3) => builtin_rcall!(fns, env, put, 3, e1, e2, e3),
-        Expr::Slice(ref e1, ref e2, ref e3) => builtin_rcall!(fns, env, slice, 3, e1, e2, e3),
-        Expr::Len(ref e) => builtin_rcall!(fns, env, len, 1, e),
-        Expr::QAlloc(ref n, ref i) => builtin_rcall!(fns, env, qalloc, 2, n, i),
-        Expr::Invoke(ref f) => {
-            compile_rev_expr(f.borrow(), fns, env)?;
-            fns.current().rcall(0);
+        Expr::Member(ref m, name) if is_module(m, fns, env) => match module_const(m, name, fns, env)? {
+            Some(c) => c.emit(fns.current()),
+            None => {
+                let ft = module_func(m, name, fns, env)?;
+                fns.current().func(ft);
+            },
         },
-        Expr::Apply(ref f, ref a) => {
-            compile_rev_expr(a.borrow(), fns, env)?;
-            compile_rev_expr(f.borrow(), fns, env)?;
-            fns.current().rcall(1);
+        Expr::Member(ref m, name) => {
+            let field = Expr::String(name);
+            builtin_call!(fns, env, get, 2, m, field);
         },
-        _ => panic!("Feature {:?} is not reversible.", expr),
     }
     Ok(())
 }
 
-fn compile_inv_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
-    match *expr {
-        Expr::Int(_) => fns.current().discard(),
-        Expr::Bool(_) => fns.current().discard(),
-        Expr::Ref(_) => fns.current().discard(),
-        Expr::Call(ref f, ref args) => {
-            fns.current().icall(args.len());
-            compile_inv_expr(f.borrow(), fns, env)?;
-            for a in args.iter().rev() {
-                compile_inv_expr(a, fns, env)?;
-            }
-        },
-        Expr::UnOp(op, ref e) => {
-            fns.current().iop1(op);
-            compile_inv_expr(e.borrow(), fns, env)?;
-        },
-        Expr::BinOp(ref e1, op, ref e2) => {
-            fns.current().iop2(op);
-            compile_inv_expr(e2.borrow(), fns, env)?;
-            compile_inv_expr(e1.borrow(), fns, env)?;
+// Compiles a statement of a reversible function, or of the predicate of a
+// with block. Whatever its inverse will need is left on aux: the reversible
+// operations save their own, and the values a scope holds are stashed as it
+// is left. Only loops whose iterations are known before they start may be
+// used, so that the inverse can run them backwards.
+fn compile_rev_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *stmt {
+        Stmt::DefVar(ref p, ref e) => {
+            compile_rev_expr(e, fns, env)?;
+            declare_pattern(p, env)?;
+            put_pattern(p, true, fns, env);
         },
-        Expr::Cat(ref e1, ref e2) => builtin_icall!(fns, env, cat, 2, e2, e1),
-        Expr::Get(ref e1, ref e2) => builtin_icall!(fns, env, get, 2, e2, e1),
-        Expr::Put(ref e1, ref e2, ref e3) => builtin_icall!(fns, env, put, 3, e3, e2, e1),
-        Expr::Slice(ref e1, ref e2, ref e3) => builtin_icall!(fns, env, slice, 3, e3, e2, e1),
-        Expr::Len(ref e) => builtin_icall!(fns, env, len, 1, e),
-        Expr::QAlloc(ref n, ref i) => builtin_icall!(fns, env, qalloc, 2, n, i),
-        Expr::Invoke(ref f) => {
-            fns.current().icall(0);
-            compile_inv_expr(f.borrow(), fns, env)?;
+        Stmt::Block(ref stmts) => {
+            env.push_scope();
+            for s in stmts.iter() {
+                compile_rev_stmt(s, fns, env)?;
+            }
+            stash_scope(fns, env);
+            env.pop_scope();
         },
-        Expr::Apply(ref f, ref a) => {
-            fns.current().icall(1);
-            compile_inv_expr(f.borrow(), fns, env)?;
-            compile_inv_expr(a.borrow(), fns, env)?;
+        Stmt::If(ref p, ref t, ref e) => {
+            compile_rev_expr(p, fns, env)?;
+            env.push_scope();
+            let pred = env.add_tmp();
+            let other;
+            let done;
+            {
+                let func = fns.current();
+                other = func.label();
+                done = func.label();
+                func.put_local(pred);
+                func.op1(UnOp::Not);
+                func.branch(other);
+            }
+            compile_rev_scoped(t.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.jump(done);
+                func.bind(other);
+            }
+            if let Some(ref e) = *e {
+                compile_rev_scoped(e.borrow(), fns, env)?;
+            }
+            fns.current().bind(done);
+            stash_scope(fns, env);
+            env.pop_scope();
         },
-        _ => panic!("Feature {:?} is not reversible.", expr),
-    }
-    Ok(())
+        Stmt::ForEach(ref p, ref e, ref b) => {
+            compile_rev_expr(e, fns, env)?;
+            env.push_scope();
+            declare_pattern(p, env)?;
+            let counter = env.add_tmp();
+            let array = env.add_tmp();
+            let start_loop;
+            let end_loop;
+            {
+                let func = fns.current();
+                start_loop = func.label();
+                end_loop = func.label();
+
+                func.put_local(array);
+            }
+            builtin_call!(fns, len, 1);
+            {
+                let func = fns.current();
+                func.put_local(counter);
+                func.int(0);
+                func.op2(BinOp::Le);
+                func.branch(end_loop);
+
+                func.bind(start_loop);
+                func.get_local(array);
+                func.get_local(counter);
+                func.int(1);
+                func.op2(BinOp::Sub);
+                func.put_local(counter);
+            }
+            builtin_call!(fns, get, 2);
+            put_pattern(p, false, fns, env);
+            compile_rev_scoped(b.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.get_local(counter);
+                func.int(0);
+                func.op2(BinOp::Gt);
+                func.branch(start_loop);
+                func.bind(end_loop);
+            }
+            stash_scope(fns, env);
+            env.pop_scope();
+        },
+        Stmt::ForLoop(id, ref s, ref e, ref b) => {
+            compile_rev_expr(s, fns, env)?;
+            env.push_scope();
+            let id = env.add_id(id)?;
+            let end = env.add_tmp();
+            let first = env.add_tmp();
+            let start_loop;
+            let end_loop;
+            {
+                let func = fns.current();
+                start_loop = func.label();
+                end_loop = func.label();
+
+                func.put_local(first);
+                func.put_local(id);
+            }
+            compile_rev_expr(e, fns, env)?;
+            {
+                let func = fns.current();
+                func.put_local(end);
+                func.op2(BinOp::Ge);
+                func.branch(end_loop);
+                func.bind(start_loop);
+            }
+            compile_rev_scoped(b.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.get_local(id);
+                func.int(1);
+                func.op2(BinOp::Add);
+                func.put_local(id);
+                func.discard();
+                func.get_local(id);
+                func.get_local(end);
+                func.op2(BinOp::Lt);
+                func.branch(start_loop);
+                func.bind(end_loop);
+            }
+            stash_scope(fns, env);
+            env.pop_scope();
+        },
+        Stmt::Ctrl(ref c, ref b) | Stmt::QIf(ref c, ref b) => {
+            compile_rev_expr(c, fns, env)?;
+            env.push_scope();
+            let control = env.add_tmp();
+            {
+                let func = fns.current();
+                func.put_local(control);
+                func.control();
+            }
+            let qif = if let Stmt::QIf(..) = *stmt { true } else { false };
+            if qif {
+                env.enter_qif();
+            }
+            compile_rev_scoped(b.borrow(), fns, env)?;
+            if qif {
+                env.leave_qif();
+            }
+            fns.current().uncontrol();
+            stash_scope(fns, env);
+            env.pop_scope();
+        },
+        Stmt::Expr(ref e) => {
+            compile_rev_expr(e, fns, env)?;
+            fns.current().stash();
+        },
+        Stmt::Labeled(_, ref s) => compile_rev_stmt(s, fns, env)?,
+        Stmt::Located(pos, ref s) => {
+            fns.current().mark(Some(pos), env.names());
+            compile_rev_stmt(s, fns, env)?;
+        },
+        Stmt::Import(..) | Stmt::DefConst(..) | Stmt::DefStruct(..) => compile_stmt(stmt, fns, env)?,
+        _ => return Err(irreversible_stmt(stmt)),
+    }
+    Ok(())
+}
+
+// Why a statement compile_rev_stmt has no case for may not be used.
+fn irreversible_stmt(stmt: &Stmt) -> String {
+    match *stmt {
+        Stmt::While(..) => "Only for and foreach loops may be used in a reversible function".to_string(),
+        // What the body leaves on aux would sit on top of what undoing the
+        // predicate needs
+        Stmt::With(..) => "With blocks may not be used in a reversible function".to_string(),
+        Stmt::Break(..) | Stmt::Continue(..) => "'break' and 'continue' may not be used in a reversible function".to_string(),
+        Stmt::Return(..) => "Reversible functions may not return early".to_string(),
+        Stmt::Print(..) => "Reversible functions may not print".to_string(),
+        Stmt::DefFunc(..) | Stmt::DefRFunc(..) => "Functions may not be defined inside a reversible function".to_string(),
+        _ => "This statement may not be used in a reversible function".to_string(),
+    }
+}
+
+// Why an expression compile_rev_expr has no case for may not be used.
+fn irreversible_expr(expr: &Expr) -> String {
+    match *expr {
+        Expr::Move(id) => format!("Variable '{}' may not be moved out of in reversible code", string_table::get(id)),
+        Expr::Assign(id, _) => format!("Variable '{}' may not be assigned to in reversible code", string_table::get(id)),
+        Expr::Array(..) => "Array literals in reversible code may only hold constants; bind the array to a variable outside it instead".to_string(),
+        Expr::Map(..) => "Map literals may not be used in reversible code".to_string(),
+        Expr::Struct(name, _) => format!("Literals of struct '{}' may not be used in reversible code", string_table::get(name)),
+        // Its forward pass would have to be run within the forward pass of
+        // this function
+        Expr::Adjoint(..) => "The adjoint of a function may not be taken in reversible code".to_string(),
+        _ => "This expression may not be used in reversible code".to_string(),
+    }
+}
+
+fn compile_rev_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *expr {
+        Expr::Null => fns.current().null(),
+        Expr::Int(i) => fns.current().int(i),
+        Expr::Float(f) => fns.current().float(f),
+        Expr::Bool(b) => fns.current().bool(b),
+        Expr::String(s) => fns.current().string(s),
+        Expr::Ref(id) => match env.find(id) {
+            Some(offset) => fns.current().get_local(offset),
+            None => match fns.lookup_const(id) {
+                Some(c) => c.emit(fns.current()),
+                None => match fns.lookup(id) {
+                    Some(ft) => fns.current().func(ft),
+                    None => return_error!("Identifier '{}' is not defined", string_table::get(id)),
+                },
+            },
+        },
+        Expr::Call(ref f, ref args) => {
+            check_controllable(f, fns, env)?;
+            check_native_reversible(f, fns, env)?;
+            for a in args.iter() {
+                compile_rev_expr(a, fns, env)?;
+            }
+            compile_rev_expr(f.borrow(), fns, env)?;
+            fns.current().rcall(args.len());
+        },
+        Expr::UnOp(op, ref e) => {
+            compile_rev_expr(e.borrow(), fns, env)?;
+            fns.current().rop1(op);
+        },
+        Expr::BinOp(ref e1, op, ref e2) => {
+            compile_rev_expr(e1.borrow(), fns, env)?;
+            compile_rev_expr(e2.borrow(), fns, env)?;
+            fns.current().rop2(op);
+        },
+        Expr::Cat(ref e1, ref e2) => builtin_rcall!(fns, env, cat, 2, e1, e2),
+        Expr::Get(ref e1, ref e2) => builtin_rcall!(fns, env, get, 2, e1, e2),
+        Expr::Put(ref e1, ref e2, ref e3) => builtin_rcall!(fns, env, put, 3, e1, e2, e3),
+        Expr::Slice(ref e1, ref e2, ref e3, ref e4) => builtin_rcall!(fns, env, slice, 4, e1, e2, e3, e4),
+        Expr::Len(ref e) => builtin_rcall!(fns, env, len, 1, e),
+        Expr::QAlloc(ref n, ref i) => builtin_rcall!(fns, env, qalloc, 2, n, i),
+        Expr::Invoke(ref f) => {
+            check_controllable(f, fns, env)?;
+            check_native_reversible(f, fns, env)?;
+            compile_rev_expr(f.borrow(), fns, env)?;
+            fns.current().rcall(0);
+        },
+        Expr::If(ref p, ref t, ref e) => {
+            compile_rev_expr(p.borrow(), fns, env)?;
+            env.push_scope();
+            let pred = env.add_tmp();
+            let then;
+            let done;
+            {
+                let func = fns.current();
+                then = func.label();
+                done = func.label();
+                func.put_local(pred);
+                func.branch(then);
+            }
+            compile_rev_expr(e.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.jump(done);
+                func.bind(then);
+            }
+            compile_rev_expr(t.borrow(), fns, env)?;
+            fns.current().bind(done);
+            stash_scope(fns, env);
+            env.pop_scope();
+        },
+        Expr::Block(ref stmts, ref e) => {
+            env.push_scope();
+            for s in stmts.iter() {
+                compile_rev_stmt(s, fns, env)?;
+            }
+            compile_rev_expr(e.borrow(), fns, env)?;
+            stash_scope(fns, env);
+            env.pop_scope();
+        },
+        Expr::Member(ref m, name) if is_module(m, fns, env) => match module_const(m, name, fns, env)? {
+            Some(c) => c.emit(fns.current()),
+            None => {
+                let ft = module_func(m, name, fns, env)?;
+                fns.current().func(ft);
+            },
+        },
+        Expr::Member(ref m, name) => {
+            let field = Expr::String(name);
+            builtin_rcall!(fns, env, get, 2, m, field);
+        },
+        Expr::Apply(ref f, ref a) => {
+            check_controllable(f, fns, env)?;
+            check_native_reversible(f, fns, env)?;
+            compile_rev_expr(a.borrow(), fns, env)?;
+            compile_rev_expr(f.borrow(), fns, env)?;
+            fns.current().rcall(1);
+        },
+        Expr::Tuple(ref args) => {
+            for a in args.iter() {
+                compile_rev_expr(a, fns, env)?;
+            }
+            fns.current().tuple(args.len());
+        },
+        // A constant array, such as the matrix given to gate, holds nothing
+        // to undo, so the inverse just drops it
+        Expr::Array(..) => match constant::eval(expr, fns) {
+            Ok(c) => c.emit(fns.current()),
+            Err(_) => return Err(irreversible_expr(expr)),
+        },
+        _ => return Err(irreversible_expr(expr)),
+    }
+    Ok(())
+}
+
+// Compiles the inverse of a statement compiled by compile_rev_stmt, which
+// undoes it by running it backwards.
+fn compile_inv_stmt(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *stmt {
+        Stmt::DefVar(ref p, ref e) => {
+            get_pattern(p, fns, env);
+            compile_inv_expr(e, fns, env)?;
+        },
+        Stmt::Block(ref stmts) => {
+            env.push_scope();
+            declare_vars(stmts.iter(), env)?;
+            unstash_scope(fns, env);
+            for s in stmts.iter().rev() {
+                compile_inv_stmt(s, fns, env)?;
+            }
+            env.pop_scope();
+        },
+        Stmt::If(ref p, ref t, ref e) => {
+            env.push_scope();
+            let pred = env.add_tmp();
+            unstash_scope(fns, env);
+            let other;
+            let done;
+            {
+                let func = fns.current();
+                other = func.label();
+                done = func.label();
+                func.get_local(pred);
+                func.op1(UnOp::Not);
+                func.branch(other);
+            }
+            compile_inv_scoped(t.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.jump(done);
+                func.bind(other);
+            }
+            if let Some(ref e) = *e {
+                compile_inv_scoped(e.borrow(), fns, env)?;
+            }
+            {
+                let func = fns.current();
+                func.bind(done);
+                func.get_local(pred);
+            }
+            compile_inv_expr(p, fns, env)?;
+            env.pop_scope();
+        },
+        Stmt::ForEach(ref p, ref e, ref b) => {
+            // Goes from the first element to the last, the other way round
+            // from going forwards
+            env.push_scope();
+            declare_pattern(p, env)?;
+            let counter = env.add_tmp();
+            let array = env.add_tmp();
+            unstash_scope(fns, env);
+            let start_loop;
+            let end_loop;
+            {
+                let func = fns.current();
+                start_loop = func.label();
+                end_loop = func.label();
+
+                func.int(0);
+                func.put_local(counter);
+                func.get_local(array);
+            }
+            builtin_call!(fns, len, 1);
+            {
+                let func = fns.current();
+                func.op2(BinOp::Ge);
+                func.branch(end_loop);
+
+                func.bind(start_loop);
+                func.get_local(array);
+                func.get_local(counter);
+            }
+            builtin_call!(fns, get, 2);
+            put_pattern(p, false, fns, env);
+            compile_inv_scoped(b.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.get_local(counter);
+                func.int(1);
+                func.op2(BinOp::Add);
+                func.put_local(counter);
+                func.get_local(array);
+            }
+            builtin_call!(fns, len, 1);
+            {
+                let func = fns.current();
+                func.op2(BinOp::Lt);
+                func.branch(start_loop);
+                func.bind(end_loop);
+                func.get_local(array);
+            }
+            compile_inv_expr(e, fns, env)?;
+            env.pop_scope();
+        },
+        Stmt::ForLoop(id, ref s, ref e, ref b) => {
+            // Counts down from the end of the range
+            env.push_scope();
+            let id = env.add_id(id)?;
+            let end = env.add_tmp();
+            let first = env.add_tmp();
+            unstash_scope(fns, env);
+            let start_loop;
+            let end_loop;
+            {
+                let func = fns.current();
+                start_loop = func.label();
+                end_loop = func.label();
+
+                func.get_local(end);
+                func.put_local(id);
+                func.get_local(first);
+                func.op2(BinOp::Le);
+                func.branch(end_loop);
+
+                func.bind(start_loop);
+                func.get_local(id);
+                func.int(1);
+                func.op2(BinOp::Sub);
+                func.put_local(id);
+                func.discard();
+            }
+            compile_inv_scoped(b.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.get_local(id);
+                func.get_local(first);
+                func.op2(BinOp::Gt);
+                func.branch(start_loop);
+                func.bind(end_loop);
+                func.get_local(end);
+            }
+            compile_inv_expr(e, fns, env)?;
+            fns.current().get_local(first);
+            compile_inv_expr(s, fns, env)?;
+            env.pop_scope();
+        },
+        Stmt::Ctrl(ref c, ref b) | Stmt::QIf(ref c, ref b) => {
+            env.push_scope();
+            let control = env.add_tmp();
+            unstash_scope(fns, env);
+            {
+                let func = fns.current();
+                func.get_local(control);
+                func.control();
+            }
+            compile_inv_scoped(b.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.uncontrol();
+                func.get_local(control);
+            }
+            compile_inv_expr(c, fns, env)?;
+            env.pop_scope();
+        },
+        Stmt::Expr(ref e) => {
+            fns.current().unstash();
+            compile_inv_expr(e, fns, env)?;
+        },
+        Stmt::Labeled(_, ref s) => compile_inv_stmt(s, fns, env)?,
+        Stmt::Located(pos, ref s) => {
+            fns.current().mark(Some(pos), env.names());
+            compile_inv_stmt(s, fns, env)?;
+        },
+        _ => return Err(irreversible_stmt(stmt)),
+    }
+    Ok(())
+}
+
+fn compile_inv_expr(expr: &Expr, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *expr {
+        Expr::Null => fns.current().discard(),
+        Expr::Int(_) => fns.current().discard(),
+        Expr::Float(_) => fns.current().discard(),
+        Expr::Bool(_) => fns.current().discard(),
+        Expr::String(_) => fns.current().discard(),
+        Expr::Array(_) => fns.current().discard(),
+        Expr::Ref(_) => fns.current().discard(),
+        Expr::Member(ref m, _) if is_module(m, fns, env) => fns.current().discard(),
+        Expr::Member(ref m, name) => {
+            let field = Expr::String(name);
+            builtin_icall!(fns, env, get, 2, field, m);
+        },
+        Expr::If(ref p, ref t, ref e) => {
+            env.push_scope();
+            let pred = env.add_tmp();
+            unstash_scope(fns, env);
+            let then;
+            let done;
+            {
+                let func = fns.current();
+                then = func.label();
+                done = func.label();
+                func.get_local(pred);
+                func.branch(then);
+            }
+            compile_inv_expr(e.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.jump(done);
+                func.bind(then);
+            }
+            compile_inv_expr(t.borrow(), fns, env)?;
+            {
+                let func = fns.current();
+                func.bind(done);
+                func.get_local(pred);
+            }
+            compile_inv_expr(p.borrow(), fns, env)?;
+            env.pop_scope();
+        },
+        Expr::Block(ref stmts, ref e) => {
+            env.push_scope();
+            declare_vars(stmts.iter(), env)?;
+            unstash_scope(fns, env);
+            compile_inv_expr(e.borrow(), fns, env)?;
+            for s in stmts.iter().rev() {
+                compile_inv_stmt(s, fns, env)?;
+            }
+            env.pop_scope();
+        },
+        Expr::Call(ref f, ref args) => {
+            fns.current().icall(args.len());
+            compile_inv_expr(f.borrow(), fns, env)?;
+            for a in args.iter().rev() {
+                compile_inv_expr(a, fns, env)?;
+            }
+        },
+        Expr::UnOp(op, ref e) => {
+            fns.current().iop1(op);
+            compile_inv_expr(e.borrow(), fns, env)?;
+        },
+        Expr::BinOp(ref e1, op, ref e2) => {
+            fns.current().iop2(op);
+            compile_inv_expr(e2.borrow(), fns, env)?;
+            compile_inv_expr(e1.borrow(), fns, env)?;
+        },
+        Expr::Cat(ref e1, ref e2) => builtin_icall!(fns, env, cat, 2, e2, e1),
+        Expr::Get(ref e1, ref e2) => builtin_icall!(fns, env, get, 2, e2, e1),
+        Expr::Put(ref e1, ref e2, ref e3) => builtin_icall!(fns, env, put, 3, e3, e2, e1),
+        Expr::Slice(ref e1, ref e2, ref e3, ref e4) => builtin_icall!(fns, env, slice, 4, e4, e3, e2, e1),
+        Expr::Len(ref e) => builtin_icall!(fns, env, len, 1, e),
+        Expr::QAlloc(ref n, ref i) => builtin_icall!(fns, env, qalloc, 2, n, i),
+        Expr::Invoke(ref f) => {
+            fns.current().icall(0);
+            compile_inv_expr(f.borrow(), fns, env)?;
+        },
+        Expr::Apply(ref f, ref a) => {
+            fns.current().icall(1);
+            compile_inv_expr(f.borrow(), fns, env)?;
+            compile_inv_expr(a.borrow(), fns, env)?;
+        },
+        Expr::Tuple(ref args) => {
+            fns.current().runpack(args.len());
+            for a in args.iter().rev() {
+                compile_inv_expr(a, fns, env)?;
+            }
+        },
+        _ => return Err(irreversible_expr(expr)),
+    }
+    Ok(())
+}
+
+// Whether m.name names a function or constant of an imported module, rather
+// than a field of a struct.
+fn is_module(m: &Expr, fns: &Functions, env: &LocalEnvironment) -> bool {
+    match *m {
+        Expr::Ref(id) => env.find(id).is_none() && fns.is_module(id),
+        _ => false,
+    }
+}
+
+// Resolves module.name to a function of an imported module.
+fn module_func(m: &Expr, name: StringToken, fns: &Functions, env: &LocalEnvironment) -> Result<FunctionToken, String> {
+    match *m {
+        Expr::Ref(id) if env.find(id).is_none() => fns.lookup_in(id, name),
+        _ => return_error!("Only the functions and constants of imported modules may be named with '.'"),
+    }
+}
+
+// Resolves module.name to a constant of an imported module, if it is one.
+fn module_const(m: &Expr, name: StringToken, fns: &Functions, env: &LocalEnvironment) -> Result<Option<Constant>, String> {
+    match *m {
+        Expr::Ref(id) if env.find(id).is_none() => fns.lookup_const_in(id, name),
+        _ => return_error!("Only the functions and constants of imported modules may be named with '.'"),
+    }
+}
+
+// Finds the function which an expression names, along with its name, if it
+// is a plain reference to one or a member of an imported module.
+fn named_func(f: &Expr, fns: &Functions, env: &LocalEnvironment) -> Result<Option<(StringToken, FunctionToken)>, String> {
+    match *f {
+        Expr::Ref(id) if env.find(id).is_none() && fns.lookup_const(id).is_none() => {
+            Ok(fns.lookup(id).map(|ft| (id, ft)))
+        },
+        Expr::Member(ref m, name) if is_module(m, fns, env) => {
+            if module_const(m, name, fns, env)?.is_some() {
+                return Ok(None);
+            }
+            Ok(Some((name, module_func(m, name, fns, env)?)))
+        },
+        _ => Ok(None),
+    }
+}
+
+// Reports a call of a builtin with no inverse from reversible code. User
+// functions are left to the interpreter, as one named here may be a
+// reversible function whose body has not been compiled yet.
+fn check_native_reversible(f: &Expr, fns: &Functions, env: &LocalEnvironment) -> Result<(), String> {
+    if let Some((name, ft)) = named_func(f, fns, env)? {
+        if ft.is_native() && !fns.is_reversible(ft) {
+            return_error!("Function '{}' is not reversible", string_table::get(name));
+        }
+    }
+    Ok(())
+}
+
+// Reports a call of a builtin such as measure from within a qif, where only
+// gates may be applied, or of a function already compiled which calls one.
+// Elsewhere, such a call marks the function making it in turn. Calls the
+// compiler can't follow, as through a variable, are left to the interpreter.
+fn check_controllable(f: &Expr, fns: &mut Functions, env: &LocalEnvironment) -> Result<(), String> {
+    let (name, ft) = match named_func(f, fns, env)? {
+        Some(named) => named,
+        None => return Ok(()),
+    };
+    let uncontrollable = if ft.is_native() {
+        UNCONTROLLABLE.contains(&IRT_STRINGS[ft.to_native_index()])
+    } else {
+        fns.is_uncontrollable(ft)
+    };
+    if uncontrollable {
+        if env.in_qif() {
+            return_error!("'{}' may not be used inside a qif block", string_table::get(name));
+        }
+        fns.mark_uncontrollable();
+    }
+    Ok(())
+}
+
+// Compiles a statement in a scope of its own, so that any variable it
+// declares is stashed once it is done.
+fn compile_rev_scoped(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    env.push_scope();
+    compile_rev_stmt(stmt, fns, env)?;
+    stash_scope(fns, env);
+    env.pop_scope();
+    Ok(())
+}
+
+fn compile_inv_scoped(stmt: &Stmt, fns: &mut Functions, env: &mut LocalEnvironment) -> Result<(), String> {
+    env.push_scope();
+    declare_vars(Some(stmt).into_iter(), env)?;
+    unstash_scope(fns, env);
+    compile_inv_stmt(stmt, fns, env)?;
+    env.pop_scope();
+    Ok(())
+}
+
+// Declares the variables of a scope up front, since going backwards they are
+// needed before the statements defining them are reached.
+fn declare_vars<'a, I: Iterator<Item=&'a Stmt>>(stmts: I, env: &mut LocalEnvironment) -> Result<(), String> {
+    for s in stmts {
+        if let Stmt::DefVar(ref p, _) = *s.unlocated() {
+            declare_pattern(p, env)?;
+        }
+    }
+    Ok(())
+}
+
+// Gives each parameter the slot its argument is passed in, and each name in
+// a parameter's pattern a slot after them.
+fn declare_params(params: &LinkedList<Pattern>, env: &mut LocalEnvironment) -> Result<(), String> {
+    for p in params.iter() {
+        match *p {
+            Pattern::Name(id) => { env.add_id(id)?; },
+            Pattern::Tuple(_) => { env.add_tmp(); },
+        }
+    }
+    for p in params.iter() {
+        if let Pattern::Tuple(_) = *p {
+            declare_pattern(p, env)?;
+        }
+    }
+    Ok(())
+}
+
+// Takes apart the arguments passed for parameters which are patterns.
+fn unpack_params(params: &LinkedList<Pattern>, fns: &mut Functions, env: &LocalEnvironment) {
+    for (slot, p) in params.iter().enumerate() {
+        if let Pattern::Tuple(_) = *p {
+            fns.current().get_local(slot);
+            put_pattern(p, false, fns, env);
+        }
+    }
+}
+
+// Adds a slot to the innermost scope for each name in a pattern.
+fn declare_pattern(p: &Pattern, env: &mut LocalEnvironment) -> Result<(), String> {
+    match *p {
+        Pattern::Name(id) => { env.add_id(id)?; },
+        Pattern::Tuple(ref ps) => {
+            for p in ps.iter() {
+                declare_pattern(p, env)?;
+            }
+        },
+    }
+    Ok(())
+}
+
+// Binds the names of a declared pattern to the value on top of the stack,
+// which is taken off. A reversible pattern only takes apart tuples, since
+// get_pattern gathers its names back into one.
+fn put_pattern(p: &Pattern, rev: bool, fns: &mut Functions, env: &LocalEnvironment) {
+    match *p {
+        Pattern::Name(id) => {
+            let func = fns.current();
+            func.put_local(env.find(id).unwrap());
+            func.discard();
+        },
+        Pattern::Tuple(ref ps) => {
+            if rev {
+                fns.current().runpack(ps.len());
+            } else {
+                fns.current().unpack(ps.len());
+            }
+            // The last part comes off first
+            for p in ps.iter().rev() {
+                put_pattern(p, rev, fns, env);
+            }
+        },
+    }
+}
+
+// Gathers what the names of a pattern are bound to back into one value, as
+// the inverse of put_pattern.
+fn get_pattern(p: &Pattern, fns: &mut Functions, env: &LocalEnvironment) {
+    match *p {
+        Pattern::Name(id) => fns.current().get_local(env.find(id).unwrap()),
+        Pattern::Tuple(ref ps) => {
+            for p in ps.iter() {
+                get_pattern(p, fns, env);
+            }
+            fns.current().tuple(ps.len());
+        },
+    }
+}
+
+// Hands the values held by the innermost scope over to aux as it is left,
+// for the inverse to take back as it enters the scope.
+fn stash_scope(fns: &mut Functions, env: &LocalEnvironment) {
+    let func = fns.current();
+    for slot in env.scope_slots() {
+        func.get_local(slot);
+        func.stash();
+    }
+}
+
+fn unstash_scope(fns: &mut Functions, env: &LocalEnvironment) {
+    let func = fns.current();
+    for slot in env.scope_slots().rev() {
+        func.unstash();
+        func.put_local(slot);
+        func.discard();
+    }
 }
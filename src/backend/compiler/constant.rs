@@ -0,0 +1,199 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use frontend::ast::*;
+use backend::compiler::function::{Function, Functions};
+use backend::compiler::optimizer;
+use backend::runtime::{IRT_STRINGS, IRT_TABLE, NoCaller};
+use backend::runtime::limits;
+use backend::runtime::value::Value;
+use util::string_table::{self, StringToken};
+
+use std::borrow::Borrow;
+use std::fmt::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::vec::Vec;
+
+// Builtins which always give the same result for the same arguments, and so
+// may be used to compute a constant.
+const PURE_FUNCTIONS: &'static [&'static str] = &[
+    "ceil", "floor", "round", "abs", "ln", "log2", "log10", "sqrt",
+    "cos", "sin", "tan", "acos", "asin", "atan", "pow", "pi", "e",
+];
+
+// The value of a constant, known at compile time and built afresh wherever
+// the constant is used.
+#[derive(Clone, Debug)]
+pub enum Constant {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(StringToken),
+    Array(Vec<Constant>),
+}
+
+impl Constant {
+    fn from_value(v: Value) -> Option<Constant> {
+        match v {
+            Value::Int(v) => Some(Constant::Int(v)),
+            Value::Float(v) => Some(Constant::Float(v)),
+            Value::Bool(v) => Some(Constant::Bool(v)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Option<Value> {
+        match *self {
+            Constant::Int(v) => Some(Value::Int(v)),
+            Constant::Float(v) => Some(Value::Float(v)),
+            Constant::Bool(v) => Some(Value::Bool(v)),
+            Constant::String(_) | Constant::Array(_) => None,
+        }
+    }
+
+    pub fn emit(&self, func: &mut Function) {
+        match *self {
+            Constant::Int(v) => func.int(v),
+            Constant::Float(v) => func.float(v),
+            Constant::Bool(v) => func.bool(v),
+            Constant::String(v) => func.string(v),
+            Constant::Array(ref cs) => {
+                for c in cs.iter() {
+                    c.emit(func);
+                }
+                func.array(cs.len());
+            },
+        }
+    }
+}
+
+// Evaluates the expression a constant is declared with. It may only involve
+// literals, other constants and pure builtins, as nothing else exists before
+// the program runs.
+pub fn eval(expr: &Expr, fns: &Functions) -> Result<Constant, String> {
+    match *expr {
+        Expr::Int(v) => Ok(Constant::Int(v)),
+        Expr::Float(v) => Ok(Constant::Float(v)),
+        Expr::Bool(v) => Ok(Constant::Bool(v)),
+        Expr::String(v) => Ok(Constant::String(v)),
+        Expr::Ref(id) => match fns.lookup_const(id) {
+            Some(c) => Ok(c),
+            None => return_error!("'{}' is not a constant", string_table::get(id)),
+        },
+        Expr::Member(ref m, name) => {
+            if let Expr::Ref(module) = **m {
+                if let Some(c) = fns.lookup_const_in(module, name)? {
+                    return Ok(c);
+                }
+            }
+            return_error!("'{}' is not a constant", string_table::get(name))
+        },
+        Expr::Array(ref args) => {
+            let mut cs = Vec::new();
+            for a in args.iter() {
+                cs.push(eval(a, fns)?);
+            }
+            Ok(Constant::Array(cs))
+        },
+        Expr::UnOp(op, ref e) => {
+            let v = scalar(eval(e.borrow(), fns)?)?;
+            match optimizer::fold_op1(v, op).and_then(Constant::from_value) {
+                Some(c) => Ok(c),
+                None => return_error!("Invalid operand to '{:?}' in a constant", op),
+            }
+        },
+        Expr::BinOp(ref e1, op, ref e2) => {
+            let v1 = scalar(eval(e1.borrow(), fns)?)?;
+            let v2 = scalar(eval(e2.borrow(), fns)?)?;
+            match optimizer::fold_op2(v1, op, v2).and_then(Constant::from_value) {
+                Some(c) => Ok(c),
+                None => return_error!("Invalid operands to '{:?}' in a constant", op),
+            }
+        },
+        Expr::If(ref p, ref t, ref e) => match eval(p.borrow(), fns)? {
+            Constant::Bool(true) => eval(t.borrow(), fns),
+            Constant::Bool(false) => eval(e.borrow(), fns),
+            _ => return_error!("The condition of an if in a constant must be a Bool"),
+        },
+        Expr::Len(ref e) => match eval(e.borrow(), fns)? {
+            Constant::Array(cs) => Ok(Constant::Int(cs.len() as i64)),
+            _ => return_error!("Only the length of an array may be taken in a constant"),
+        },
+        Expr::Get(ref a, ref i) => match (eval(a.borrow(), fns)?, eval(i.borrow(), fns)?) {
+            (Constant::Array(mut cs), Constant::Int(i)) => {
+                let j = if i < 0 { i + cs.len() as i64 } else { i };
+                if j < 0 || j as usize >= cs.len() {
+                    return_error!("Index {} out of bounds in a constant", i);
+                }
+                Ok(cs.swap_remove(j as usize))
+            },
+            _ => return_error!("Only an array may be indexed in a constant, and only by an Int"),
+        },
+        Expr::Call(ref f, ref args) => {
+            let mut vs = Vec::new();
+            for a in args.iter() {
+                vs.push(scalar(eval(a, fns)?)?);
+            }
+            call(f, vs, fns)
+        },
+        Expr::Invoke(ref f) => call(f, Vec::new(), fns),
+        Expr::Apply(ref f, ref a) => {
+            let v = scalar(eval(a.borrow(), fns)?)?;
+            call(f, vec![v], fns)
+        },
+        _ => return_error!("The value of a constant must be known at compile time"),
+    }
+}
+
+fn scalar(c: Constant) -> Result<Value, String> {
+    match c.to_value() {
+        Some(v) => Ok(v),
+        None => return_error!("Only numbers and Bools may be operated on in a constant"),
+    }
+}
+
+fn call(f: &Expr, mut args: Vec<Value>, fns: &Functions) -> Result<Constant, String> {
+    let ft = match *f {
+        Expr::Ref(id) => fns.lookup(id),
+        _ => None,
+    };
+    let index = match ft {
+        Some(ft) if ft.is_native() && PURE_FUNCTIONS.contains(&IRT_STRINGS[ft.to_native_index()]) => ft.to_native_index(),
+        _ => return_error!("Only pure builtins such as sqrt may be called in a constant"),
+    };
+    if IRT_TABLE[index].arity != args.len() {
+        return_error!("Function '{}' takes {} arguments", IRT_STRINGS[index], IRT_TABLE[index].arity);
+    }
+    for a in args.iter() {
+        match *a {
+            Value::Int(_) | Value::Float(_) => (),
+            _ => return_error!("Function '{}' takes numbers", IRT_STRINGS[index]),
+        }
+    }
+    // The builtin runs at compile time, so whatever stops it must become a
+    // compile error rather than bring down the compiler
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        (IRT_TABLE[index].entry.irr)(&mut args, &mut NoCaller);
+    }));
+    if let Some(err) = limits::take_error() {
+        return_error!("In a call of '{}': {}", IRT_STRINGS[index], err);
+    }
+    if result.is_err() {
+        return_error!("Function '{}' failed on the arguments it was given", IRT_STRINGS[index]);
+    }
+    Ok(Constant::from_value(args.pop().unwrap()).unwrap())
+}
@@ -19,6 +19,7 @@ use util::string_table::{self, StringToken};
 
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::ops::Range;
 use std::vec::Vec;
 
 pub struct Environment<T> {
@@ -42,6 +43,11 @@ impl<T: Copy> Environment<T> {
         let _ = self.ids.pop().unwrap();
     }
 
+    // Pops the innermost scope, handing back what was defined in it.
+    pub fn take_scope(&mut self) -> HashMap<StringToken, T> {
+        self.ids.pop().unwrap()
+    }
+
     pub fn add(&mut self, id: StringToken, data: T) -> Result<(), String> {
         let l = self.ids.len();
         if self.ids[l-1].contains_key(&id) {
@@ -51,6 +57,11 @@ impl<T: Copy> Environment<T> {
         Ok(())
     }
 
+    // Whether the innermost scope defines the identifier.
+    pub fn defined(&self, id: StringToken) -> bool {
+        self.ids[self.ids.len()-1].contains_key(&id)
+    }
+
     pub fn find(&self, id: StringToken) -> Option<T> {
         for hm in self.ids.iter().rev() {
             if let Some(v) = hm.get(&id) {
@@ -59,6 +70,24 @@ impl<T: Copy> Environment<T> {
         }
         None
     }
+
+    pub fn visible(&self) -> Vec<(StringToken, T)> {
+        let mut all = HashMap::new();
+        for hm in self.ids.iter() {
+            for (id, v) in hm.iter() {
+                all.insert(*id, *v);
+            }
+        }
+        all.into_iter().collect()
+    }
+}
+
+// Something a break or continue might jump out of: either a loop, with its
+// label and where each statement lands, or something which must not be left
+// early, such as a with block.
+enum Exit {
+    Loop(Option<StringToken>, usize, usize),
+    Barrier(&'static str),
 }
 
 pub struct LocalEnvironment {
@@ -66,6 +95,8 @@ pub struct LocalEnvironment {
     id_count: Vec<usize>,
     id_total: usize,
     id_max: usize,
+    exits: Vec<Exit>,
+    qifs: usize,
 }
 
 impl LocalEnvironment {
@@ -76,7 +107,9 @@ impl LocalEnvironment {
             ids: Environment::new(),
             id_count: id_count,
             id_total: 0,
-            id_max: 0
+            id_max: 0,
+            exits: Vec::new(),
+            qifs: 0,
         }
     }
 
@@ -111,7 +144,70 @@ impl LocalEnvironment {
         self.id_max
     }
 
+    // The frame slots of what the innermost scope defines.
+    pub fn scope_slots(&self) -> Range<usize> {
+        let l = self.id_count.len();
+        self.id_total - self.id_count[l-1]..self.id_total
+    }
+
     pub fn find(&self, id: StringToken) -> Option<usize> {
         self.ids.find(id)
     }
+
+    pub fn push_loop(&mut self, label: Option<StringToken>, brk: usize, cont: usize) {
+        self.exits.push(Exit::Loop(label, brk, cont));
+    }
+
+    // Stops break and continue from jumping out of what is being compiled,
+    // named by what.
+    pub fn push_barrier(&mut self, what: &'static str) {
+        self.exits.push(Exit::Barrier(what));
+    }
+
+    pub fn pop_exit(&mut self) {
+        let _ = self.exits.pop().unwrap();
+    }
+
+    // Finds where a break or continue (as stmt) to the given loop, or the
+    // innermost one, should jump to.
+    pub fn find_exit(&self, stmt: &str, label: Option<StringToken>) -> Result<usize, String> {
+        let mut barrier = None;
+        for exit in self.exits.iter().rev() {
+            match *exit {
+                Exit::Loop(l, brk, cont) => if label.is_none() || label == l {
+                    if let Some(what) = barrier {
+                        return_error!("'{}' may not jump out of {}", stmt, what);
+                    }
+                    return Ok(if stmt == "break" { brk } else { cont });
+                },
+                Exit::Barrier(what) => if barrier.is_none() {
+                    barrier = Some(what);
+                },
+            }
+        }
+        match label {
+            Some(label) => return_error!("No enclosing loop is labelled '{}'", string_table::get(label)),
+            None => return_error!("'{}' may only be used inside a loop", stmt),
+        }
+    }
+
+    pub fn enter_qif(&mut self) {
+        self.qifs += 1;
+    }
+
+    pub fn leave_qif(&mut self) {
+        self.qifs -= 1;
+    }
+
+    // Whether what is being compiled is within the body of a qif, which may
+    // only apply gates.
+    pub fn in_qif(&self) -> bool {
+        self.qifs > 0
+    }
+
+    pub fn names(&self) -> Vec<(StringToken, usize)> {
+        let mut names = self.ids.visible();
+        names.sort_by_key(|&(_, offset)| offset);
+        names
+    }
 }
@@ -16,29 +16,40 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use backend::bytecode::*;
+use backend::compiler::constant::Constant;
 use backend::compiler::environment::Environment;
+use backend::compiler::optimizer;
 use backend::runtime::{IRT_STRINGS, IRT_TABLE};
+use backend::runtime::structure::StructLayout;
 use util::ops::*;
 use util::string_table::{self, StringToken};
 
+use std::collections::{HashMap, HashSet, LinkedList};
+use std::fmt::Write;
+use std::mem;
+use std::rc::Rc;
 use std::vec::Vec;
 
 type LabelToken = usize;
 
 #[derive(Debug)]
 pub struct Function {
+    name: Option<StringToken>,
     bc: Vec<Bytecode>,
     labels: Vec<Option<usize>>,
+    debug: Vec<DebugEntry>,
     arity: usize,
     locals: usize,
     inverse: Option<usize>
 }
 
 impl Function {
-    pub fn new(arity: usize) -> Function {
+    pub fn new(name: Option<StringToken>, arity: usize) -> Function {
         Function {
+            name: name,
             bc: Vec::new(),
             labels: Vec::new(),
+            debug: Vec::new(),
             arity: arity,
             locals: 0,
             inverse: None
@@ -52,7 +63,13 @@ impl Function {
     pub fn float(&mut self, v: f64)          { self.bc.push(Bytecode::Float(v));           } 
     pub fn bool(&mut self, v: bool)          { self.bc.push(Bytecode::Bool(v));            } 
     pub fn func(&mut self, f: FunctionToken) { self.bc.push(Bytecode::Func(f));            }
+    pub fn string(&mut self, s: StringToken) { self.bc.push(Bytecode::String(s));          }
     pub fn array(&mut self, len: usize)      { self.bc.push(Bytecode::Array(len));         }
+    pub fn tuple(&mut self, len: usize)      { self.bc.push(Bytecode::Tuple(len));         }
+    pub fn unpack(&mut self, len: usize)     { self.bc.push(Bytecode::Unpack(Call::Regular, len)); }
+    pub fn runpack(&mut self, len: usize)    { self.bc.push(Bytecode::Unpack(Call::Reverse, len)); }
+    pub fn map(&mut self, len: usize)        { self.bc.push(Bytecode::Map(len));           }
+    pub fn struct_(&mut self, l: Rc<StructLayout>) { self.bc.push(Bytecode::Struct(l));    }
     pub fn op2(&mut self, op: BinOp)         { self.bc.push(Bytecode::Op2(Call::Regular, op)); } 
     pub fn op1(&mut self, op: UnOp)          { self.bc.push(Bytecode::Op1(Call::Regular, op)); }
     pub fn rop2(&mut self, op: BinOp)        { self.bc.push(Bytecode::Op2(Call::Reverse, op)); } 
@@ -63,6 +80,11 @@ impl Function {
     pub fn rcall(&mut self, arity: usize)    { self.bc.push(Bytecode::Call(Call::Reverse, arity)); } 
     pub fn icall(&mut self, arity: usize)    { self.bc.push(Bytecode::Call(Call::Inverse, arity)); } 
     pub fn return_(&mut self)                { self.bc.push(Bytecode::Return);             }
+    pub fn save(&mut self, n: usize)         { self.bc.push(Bytecode::Save(n));            }
+    pub fn stash(&mut self)                  { self.bc.push(Bytecode::Stash);              }
+    pub fn unstash(&mut self)                { self.bc.push(Bytecode::Unstash);            }
+    pub fn control(&mut self)                { self.bc.push(Bytecode::Control);            }
+    pub fn uncontrol(&mut self)              { self.bc.push(Bytecode::Uncontrol);          }
     pub fn discard(&mut self)                { self.bc.push(Bytecode::Discard);            }
     pub fn put_local(&mut self, o: usize)    { self.bc.push(Bytecode::PutLocal(o));        }
     pub fn get_local(&mut self, o: usize)    { self.bc.push(Bytecode::GetLocal(o));        }
@@ -76,10 +98,21 @@ impl Function {
     }
 
     pub fn bind(&mut self, l: LabelToken) {
-        let pos = self.pos()-1;
+        let pos = self.pos();
         self.labels[l] = Some(pos);
     }
 
+    pub fn mark(&mut self, pos: Option<usize>, locals: Vec<(StringToken, usize)>) {
+        let pc = self.pos();
+        self.debug.push(DebugEntry { pc: pc, pos: pos, locals: locals });
+    }
+
+    // Drops the entries for the statements compiled so far, whose positions
+    // don't refer to the program's source, keeping those for function entry.
+    pub fn forget_statements(&mut self) {
+        self.debug.retain(|e| e.pos.is_none());
+    }
+
     pub fn set_locals(&mut self, l: usize) {
         self.locals = l;
     }
@@ -100,24 +133,56 @@ impl Function {
         self.arity
     }
 
-    pub fn resolve(mut self) -> Vec<Bytecode> { 
+    pub fn name(&self) -> Option<StringToken> {
+        self.name
+    }
+
+    pub fn optimize(&mut self) {
+        let mut targets = vec![false; self.bc.len() + 1];
+        for l in self.labels.iter() {
+            if let Some(pos) = *l {
+                targets[pos] = true;
+            }
+        }
+        if let Some(pos) = self.inverse {
+            targets[pos] = true;
+        }
+        let bc = mem::replace(&mut self.bc, Vec::new());
+        let (bc, remap) = optimizer::optimize(bc, &targets[..]);
+        self.bc = bc;
+        for l in self.labels.iter_mut() {
+            if let Some(pos) = *l {
+                *l = Some(remap[pos]);
+            }
+        }
+        self.inverse = self.inverse.map(|pos| remap[pos]);
+        // Statement boundaries don't survive rewrites, so debugging always
+        // runs unoptimized code.
+        self.debug.clear();
+    }
+
+    pub fn resolve(mut self) -> (Vec<Bytecode>, Vec<DebugEntry>) { 
         let mut i: isize = 0;
         for b in self.bc.iter_mut() {
             let bcopy = (*b).clone();
             match bcopy {
                 Bytecode::Branch(o) => *b = match self.labels[o as usize] {
-                    Some(o) => Bytecode::Branch((o as isize) - i + 1),
+                    Some(o) => Bytecode::Branch((o as isize) - i),
+                    None => panic!("Internal Error: Found unbound label!"),
+                },
+                Bytecode::BranchFalse(o) => *b = match self.labels[o as usize] {
+                    Some(o) => Bytecode::BranchFalse((o as isize) - i),
                     None => panic!("Internal Error: Found unbound label!"),
                 },
                 Bytecode::Jump(o) => *b = match self.labels[o as usize] {
-                    Some(o) => Bytecode::Jump((o as isize) - i + 1),
+                    Some(o) => Bytecode::Jump((o as isize) - i),
                     None => panic!("Internal Error: Found unbound label!"),
                 },
                 _ => (),
             }
             i += 1;
         }
-        self.bc
+        (self.bc, self.debug)
     }
 }
 
@@ -126,6 +191,17 @@ pub struct Functions {
     ctx: Vec<FunctionToken>,
     fns: Vec<Function>,
     env: Environment<FunctionToken>,
+    // The constants of the module being compiled
+    consts: HashMap<StringToken, Constant>,
+    // The structs of the module being compiled
+    structs: HashMap<StringToken, Rc<StructLayout>>,
+    // The functions and constants each module defines, and the modules
+    // visible to the one being compiled by the names they were imported under
+    modules: Vec<(HashMap<StringToken, FunctionToken>, HashMap<StringToken, Constant>)>,
+    imports: HashMap<StringToken, usize>,
+    // Functions found to measure, or to do anything else which can't be
+    // controlled, by calling a builtin or function which does
+    uncontrollable: HashSet<FunctionToken>,
 }
 
 impl Functions {
@@ -134,7 +210,7 @@ impl Functions {
         let mut fns = Vec::new();
         let mut ctx = Vec::new();
         let mut env = Environment::new();
-        let top = Function::new(0);
+        let top = Function::new(None, 0);
         fns.push(top);
         ctx.push(ftg);
         let mut i = 0;
@@ -148,6 +224,11 @@ impl Functions {
             ctx: ctx,
             fns: fns,
             env: env,
+            consts: HashMap::new(),
+            structs: HashMap::new(),
+            modules: Vec::new(),
+            imports: HashMap::new(),
+            uncontrollable: HashSet::new(),
         }
     }
 
@@ -158,41 +239,166 @@ impl Functions {
     }
 
     pub fn push_func(&mut self, name: StringToken, arity: usize) -> Result<(), String> {
+        if self.consts.contains_key(&name) {
+            return_error!("Illegal redefinition of identifier '{}'", string_table::get(name));
+        }
         self.env.add(name, self.ftg)?;
         self.ctx.push(self.ftg);
-        let f = Function::new(arity);
+        let f = Function::new(Some(name), arity);
         self.fns.push(f);
         self.env.push_scope();
         self.ftg = self.ftg.inc();
         Ok(())
     }
 
+    // Marks the function being compiled as one which may not be called in
+    // the body of a qif.
+    pub fn mark_uncontrollable(&mut self) {
+        let l = self.ctx.len();
+        let ft = self.ctx[l-1];
+        self.uncontrollable.insert(ft);
+    }
+
+    pub fn is_uncontrollable(&self, ft: FunctionToken) -> bool {
+        self.uncontrollable.contains(&ft)
+    }
+
     pub fn pop_func(&mut self) {
         let _ = self.ctx.pop();
         let _ = self.env.pop_scope();
     }
 
+    // Drops the debug entries for the statements compiled so far, which come
+    // from the prelude or a module rather than the program's source.
+    pub fn forget_statements(&mut self) {
+        for f in self.fns.iter_mut() {
+            f.forget_statements();
+        }
+    }
+
+    // Starts a module, or the program itself, whose functions go into a new
+    // scope, where they may shadow the prelude's.
+    pub fn begin_module(&mut self, imports: &HashMap<StringToken, usize>) {
+        self.imports = imports.clone();
+        self.consts = HashMap::new();
+        self.structs = HashMap::new();
+        self.env.push_scope();
+    }
+
+    pub fn end_module(&mut self) {
+        self.forget_statements();
+        let defined = self.env.take_scope();
+        let consts = mem::replace(&mut self.consts, HashMap::new());
+        self.modules.push((defined, consts));
+    }
+
+    // Declares a constant of the module being compiled, which may not share
+    // its name with any of the module's functions.
+    pub fn define_const(&mut self, name: StringToken, value: Constant) -> Result<(), String> {
+        if self.consts.contains_key(&name) || self.env.defined(name) {
+            return_error!("Illegal redefinition of identifier '{}'", string_table::get(name));
+        }
+        self.consts.insert(name, value);
+        Ok(())
+    }
+
+    // Declares a struct of the module being compiled. Structs are only ever
+    // named where they are built, so they may share names with functions.
+    pub fn define_struct(&mut self, name: StringToken, fields: &LinkedList<StringToken>) -> Result<(), String> {
+        if self.structs.contains_key(&name) {
+            return_error!("Illegal redefinition of struct '{}'", string_table::get(name));
+        }
+        let mut layout = Vec::new();
+        for &f in fields.iter() {
+            if layout.contains(&f) {
+                return_error!("Struct '{}' has more than one field named '{}'", string_table::get(name), string_table::get(f));
+            }
+            layout.push(f);
+        }
+        self.structs.insert(name, Rc::new(StructLayout { name: name, fields: layout }));
+        Ok(())
+    }
+
+    pub fn lookup_struct(&self, name: StringToken) -> Option<Rc<StructLayout>> {
+        self.structs.get(&name).cloned()
+    }
+
+    // Whether a name refers to an imported module.
+    pub fn is_module(&self, name: StringToken) -> bool {
+        self.imports.contains_key(&name)
+    }
+
+    fn module(&self, module: StringToken) -> Result<usize, String> {
+        match self.imports.get(&module) {
+            Some(&m) => Ok(m),
+            None => return_error!("No module named '{}' has been imported", string_table::get(module)),
+        }
+    }
+
+    // Finds a function of the module imported as the given name.
+    pub fn lookup_in(&self, module: StringToken, name: StringToken) -> Result<FunctionToken, String> {
+        let m = self.module(module)?;
+        match self.modules[m].0.get(&name) {
+            Some(&ft) => Ok(ft),
+            None => return_error!("Module '{}' has no function or constant '{}'", string_table::get(module), string_table::get(name)),
+        }
+    }
+
+    // Finds a constant of the module imported as the given name, if it has
+    // one by that name.
+    pub fn lookup_const_in(&self, module: StringToken, name: StringToken) -> Result<Option<Constant>, String> {
+        let m = self.module(module)?;
+        Ok(self.modules[m].1.get(&name).cloned())
+    }
+
+    pub fn lookup_const(&self, name: StringToken) -> Option<Constant> {
+        self.consts.get(&name).cloned()
+    }
+
     pub fn lookup(&self, name: StringToken) -> Option<FunctionToken> {
         self.env.find(name)
     }
 
-    pub fn to_program(self) -> Program {
-        let Functions { ftg: _, ctx: _, fns, env: _ } = self; 
+    // Finds a builtin, even where a function of the program shadows it.
+    pub fn builtin(&self, name: StringToken) -> Option<FunctionToken> {
+        let name = string_table::get(name);
+        IRT_STRINGS.iter().position(|s| **s == *name).map(FunctionToken::from_index)
+    }
+
+    // Whether a function has an inverse. A reversible function is still being
+    // compiled while its body refers to it, so it counts as well.
+    pub fn is_reversible(&self, ft: FunctionToken) -> bool {
+        if ft.is_native() {
+            return IRT_TABLE[ft.to_native_index()].entry.reversible;
+        }
+        self.ctx.contains(&ft) || self.fns[ft.to_call_index()].inverse().is_some()
+    }
+
+    pub fn to_program(self, optimize: bool) -> Program {
+        let Functions { ftg: _, ctx: _, fns, env: _, consts: _, structs: _, modules: _, imports: _, uncontrollable: _ } = self;
         let mut instructions = Vec::new();
         let mut call_table = Vec::new();
-        for func in fns.into_iter() {
+        let mut debug = Vec::new();
+        for mut func in fns.into_iter() {
+            if optimize {
+                func.optimize();
+            }
             let start = instructions.len();
+            let name = func.name();
             let arity = func.arity();
             let locals = func.locals();
             let ioffset = func.inverse();
-            instructions.extend(func.resolve().into_iter());
+            let (bc, entries) = func.resolve();
+            instructions.extend(bc.into_iter());
+            debug.extend(entries.into_iter().map(|mut e| { e.pc += start; e }));
             call_table.push(FunctionEntry {
+                name: name,
                 addr: start,
                 arity: arity,
                 locals: locals,
                 ioffset: ioffset
             });
         }
-        Program { instructions: instructions, call_table: call_table }
+        Program { instructions: instructions, call_table: call_table, debug: debug }
     }
 }
@@ -17,6 +17,8 @@
 
 mod function;
 mod environment;
+mod optimizer;
+mod constant;
 mod compiler;
 
 pub use backend::compiler::compiler::*;
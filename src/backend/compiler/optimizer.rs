@@ -0,0 +1,216 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use backend::bytecode::*;
+use backend::runtime::{IRT_STRINGS, IRT_TABLE, NoCaller};
+use backend::runtime::value::Value;
+use util::ops::*;
+
+use std::vec::Vec;
+
+// Nullary builtins which always produce the same value, and thus may be
+// evaluated at compile time.
+const PURE_CONSTANTS: &'static [&'static str] = &["pi", "e"];
+
+// Runs a peephole pass over unresolved bytecode. Instructions are pushed one
+// at a time and the tail of the output is rewritten until no pattern applies,
+// so folds cascade (e.g. `2 * 3 + 4` becomes a single constant).
+//
+// `targets` has one entry per instruction (plus one for the end of the code)
+// marking positions that jumps or the inverse entry point land on. A rewrite
+// may start at such a position but never span across one. Returns the new
+// bytecode along with a map from old positions to new positions.
+pub fn optimize(bc: Vec<Bytecode>, targets: &[bool]) -> (Vec<Bytecode>, Vec<usize>) {
+    assert_eq!(targets.len(), bc.len() + 1);
+    let mut out: Vec<Bytecode> = Vec::with_capacity(bc.len());
+    let mut fixed: Vec<bool> = Vec::with_capacity(bc.len());
+    let mut remap = Vec::with_capacity(bc.len() + 1);
+    let mut carry = false;
+    for (i, b) in bc.into_iter().enumerate() {
+        remap.push(out.len());
+        out.push(b);
+        fixed.push(targets[i] || carry);
+        carry = false;
+        while peephole(&mut out, &mut fixed, &mut carry) {}
+    }
+    remap.push(out.len());
+    (out, remap)
+}
+
+// Attempts a single rewrite on the tail of `out`. Returns whether anything
+// changed.
+fn peephole(out: &mut Vec<Bytecode>, fixed: &mut Vec<bool>, carry: &mut bool) -> bool {
+    let l = out.len();
+
+    // A window of n instructions may only be rewritten if nothing but its
+    // first instruction is a jump target.
+    let window = |n: usize| l >= n && fixed[l-n+1..].iter().all(|f| !*f);
+
+    if window(3) {
+        if let Bytecode::Op2(Call::Regular, op) = out[l-1] {
+            if let (Some(t0), Some(t1)) = (constant(&out[l-3]), constant(&out[l-2])) {
+                if let Some(b) = fold_op2(t0, op, t1).and_then(literal) {
+                    replace(out, fixed, 3, vec![b], carry);
+                    return true;
+                }
+            }
+        }
+        if let (&Bytecode::PutLocal(p), &Bytecode::Discard, &Bytecode::GetLocal(g)) = (&out[l-3], &out[l-2], &out[l-1]) {
+            if p == g {
+                replace(out, fixed, 3, vec![Bytecode::PutLocal(p)], carry);
+                return true;
+            }
+        }
+    }
+    if window(2) {
+        match (&out[l-2], &out[l-1]) {
+            (_, &Bytecode::Op1(Call::Regular, op)) => {
+                if let Some(t0) = constant(&out[l-2]) {
+                    if let Some(b) = fold_op1(t0, op).and_then(literal) {
+                        replace(out, fixed, 2, vec![b], carry);
+                        return true;
+                    }
+                }
+            },
+            (&Bytecode::Func(ft), &Bytecode::Call(Call::Regular, 0)) => {
+                if let Some(b) = pure_constant(ft) {
+                    replace(out, fixed, 2, vec![b], carry);
+                    return true;
+                }
+            },
+            _ => (),
+        }
+        match (&out[l-2], &out[l-1]) {
+            (&Bytecode::Op1(Call::Regular, UnOp::Not), &Bytecode::Branch(o)) => {
+                replace(out, fixed, 2, vec![Bytecode::BranchFalse(o)], carry);
+                return true;
+            },
+            (&Bytecode::Null, &Bytecode::Discard) |
+            (&Bytecode::Int(_), &Bytecode::Discard) |
+            (&Bytecode::Float(_), &Bytecode::Discard) |
+            (&Bytecode::Bool(_), &Bytecode::Discard) |
+            (&Bytecode::String(_), &Bytecode::Discard) |
+            (&Bytecode::Func(_), &Bytecode::Discard) |
+            (&Bytecode::GetLocal(_), &Bytecode::Discard) => {
+                replace(out, fixed, 2, Vec::new(), carry);
+                return true;
+            },
+            _ => (),
+        }
+    }
+    false
+}
+
+fn replace(out: &mut Vec<Bytecode>, fixed: &mut Vec<bool>, n: usize, with: Vec<Bytecode>, carry: &mut bool) {
+    let l = out.len();
+    let target = fixed[l-n];
+    out.truncate(l-n);
+    fixed.truncate(l-n);
+    if with.is_empty() {
+        // Whatever comes next inherits the jump target
+        *carry = *carry || target;
+    }
+    for (i, b) in with.into_iter().enumerate() {
+        out.push(b);
+        fixed.push(i == 0 && target);
+    }
+}
+
+fn constant(b: &Bytecode) -> Option<Value> {
+    match *b {
+        Bytecode::Int(v) => Some(Value::Int(v)),
+        Bytecode::Float(v) => Some(Value::Float(v)),
+        Bytecode::Bool(v) => Some(Value::Bool(v)),
+        _ => None,
+    }
+}
+
+fn literal(v: Value) -> Option<Bytecode> {
+    match v {
+        Value::Int(v) => Some(Bytecode::Int(v)),
+        Value::Float(v) => Some(Bytecode::Float(v)),
+        Value::Bool(v) => Some(Bytecode::Bool(v)),
+        _ => None,
+    }
+}
+
+fn pure_constant(ft: FunctionToken) -> Option<Bytecode> {
+    if !ft.is_native() {
+        return None;
+    }
+    let index = ft.to_native_index();
+    if !PURE_CONSTANTS.contains(&IRT_STRINGS[index]) {
+        return None;
+    }
+    let mut stack = Vec::new();
+    (IRT_TABLE[index].entry.irr)(&mut stack, &mut NoCaller);
+    stack.pop().and_then(literal)
+}
+
+fn is_numeric(v: &Value) -> bool {
+    match *v {
+        Value::Int(_) | Value::Float(_) => true,
+        _ => false,
+    }
+}
+
+fn is_logical(v: &Value) -> bool {
+    match *v {
+        Value::Int(_) | Value::Bool(_) => true,
+        _ => false,
+    }
+}
+
+// Folds a binary operation, declining anything which would fail at runtime
+// so that the error is still reported when (and if) the code executes.
+pub fn fold_op2(t0: Value, op: BinOp, t1: Value) -> Option<Value> {
+    let numeric = is_numeric(&t0) && is_numeric(&t1);
+    let logical = is_logical(&t0) && is_logical(&t1);
+    let valid = match op {
+        BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => {
+            if let (&Value::Int(x), &Value::Int(y)) = (&t0, &t1) {
+                let checked = match op {
+                    BinOp::Add => x.checked_add(y),
+                    BinOp::Sub => x.checked_sub(y),
+                    BinOp::Mul => x.checked_mul(y),
+                    BinOp::Div => x.checked_div(y),
+                    BinOp::Rem => x.checked_rem(y),
+                    _ => None,
+                };
+                checked.is_some()
+            } else {
+                numeric
+            }
+        },
+        BinOp::Pow | BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge => numeric,
+        BinOp::Eq | BinOp::Ne => numeric || logical,
+        BinOp::And | BinOp::Or => logical,
+        BinOp::BAnd | BinOp::BOr | BinOp::BXor => true,
+    };
+    if valid { Some(t0.op2(op, t1)) } else { None }
+}
+
+pub fn fold_op1(t0: Value, op: UnOp) -> Option<Value> {
+    let valid = match (op, &t0) {
+        (UnOp::Neg, &Value::Int(v)) => v.checked_neg().is_some(),
+        (UnOp::Neg, &Value::Float(_)) => true,
+        (UnOp::Not, v) => is_logical(v),
+        (UnOp::BNot, _) => true,
+        _ => false,
+    };
+    if valid { Some(t0.op1(op)) } else { None }
+}
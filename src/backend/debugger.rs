@@ -0,0 +1,289 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use backend::bytecode::*;
+use backend::interpreter::{self, Limits, Monitor, State};
+use backend::runtime::IRT_STRINGS;
+use backend::runtime::value::Value;
+use util::string_table::{self, StringToken};
+
+use std::cmp::Ordering;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+const HELP: &'static str = "\
+break <line|func>  Stop at a source line or on entry to a function (b)
+delete [n]         Delete breakpoint n, or all breakpoints (d)
+info               List breakpoints (i)
+step               Run to the next statement, entering calls (s)
+next               Run to the next statement in this frame (n)
+finish             Run until the current function returns (f)
+continue           Run until the next breakpoint (c)
+locals             Show all local variables in the current frame (l)
+print <name>       Show a local variable; quantum registers show their state (p)
+backtrace          Show the active function calls (bt)
+list               Show the current source line
+quit               Exit the program (q)";
+
+enum Breakpoint {
+    Line(usize),
+    Func(StringToken),
+}
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Continue,
+    StepInto,
+    StepOver(usize),
+    Finish(usize),
+}
+
+struct Debugger<'s> {
+    source: &'s str,
+    breakpoints: Vec<Breakpoint>,
+    mode: Mode,
+    // Names of the active Quick functions, outermost first.
+    frames: Vec<Option<StringToken>>,
+    // Set when a function breakpoint is hit, to stop at the next statement.
+    entered: bool,
+    // Depth, line and address of the last statement executed.
+    last_line: Option<(usize, usize, usize)>,
+}
+
+pub fn debug(program: Program, source: &str, limits: &Limits) -> Result<(), String> {
+    let mut debugger = Debugger {
+        source: source,
+        breakpoints: Vec::new(),
+        mode: Mode::StepInto,
+        frames: vec![None],
+        entered: false,
+        last_line: None,
+    };
+    println!("Quick debugger. Type 'help' for a list of commands.");
+    interpreter::interpret_with(program, limits, &mut debugger)?;
+    println!("Program exited.");
+    Ok(())
+}
+
+fn entry(program: &Program, pc: usize) -> Option<&DebugEntry> {
+    // Several entries may share an address when statements emit no code; the
+    // last one describes what actually executes next.
+    let i = match program.debug.binary_search_by(|e| if e.pc <= pc { Ordering::Less } else { Ordering::Greater }) {
+        Ok(i) | Err(i) => i,
+    };
+    if i > 0 && program.debug[i-1].pc == pc {
+        Some(&program.debug[i-1])
+    } else {
+        None
+    }
+}
+
+fn func_name(program: &Program, ft: FunctionToken) -> String {
+    if ft.is_native() {
+        return IRT_STRINGS[ft.to_native_index()].to_string();
+    }
+    match program.call_table[ft.to_call_index()].name {
+        Some(name) => (*string_table::get(name)).clone(),
+        None => "<top>".to_string(),
+    }
+}
+
+fn format(program: &Program, value: &Value) -> String {
+    match *value {
+        Value::Null => "null".to_string(),
+        Value::Addr(a) => format!("<addr {}>", a),
+        Value::Func(ft) => format!("<func {}>", func_name(program, ft)),
+        Value::Adjoint(ft) => format!("<func adjoint {}>", func_name(program, ft)),
+        _ => value.clone().as_string(),
+    }
+}
+
+impl<'s> Debugger<'s> {
+    fn line_of(&self, pos: usize) -> usize {
+        self.source[..pos].matches('\n').count() + 1
+    }
+
+    fn frame_name(&self, depth: usize) -> String {
+        match self.frames[depth] {
+            Some(name) => (*string_table::get(name)).clone(),
+            None => "<top>".to_string(),
+        }
+    }
+
+    fn show(&self, e: &DebugEntry) {
+        let depth = self.frames.len() - 1;
+        match e.pos {
+            Some(pos) => {
+                let line = self.line_of(pos);
+                let text = self.source.lines().nth(line - 1).unwrap_or("");
+                println!("{} at line {}:\n{:>5}  {}", self.frame_name(depth), line, line, text);
+            },
+            None => println!("{} at entry", self.frame_name(depth)),
+        }
+    }
+
+    fn add_breakpoint(&mut self, arg: &str) {
+        let bp = match arg.parse::<usize>() {
+            Ok(line) => Breakpoint::Line(line),
+            Err(_) => Breakpoint::Func(string_table::insert(arg)),
+        };
+        self.breakpoints.push(bp);
+        println!("Breakpoint {} at {}", self.breakpoints.len(), arg);
+    }
+
+    fn list_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("No breakpoints.");
+        }
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            match *bp {
+                Breakpoint::Line(line) => println!("{}: line {}", i + 1, line),
+                Breakpoint::Func(name) => println!("{}: function {}", i + 1, string_table::get(name)),
+            }
+        }
+    }
+
+    fn print_local(&self, state: &State, e: &DebugEntry, name: &str) {
+        let id = string_table::insert(name);
+        match e.locals.iter().find(|&&(local, _)| local == id) {
+            Some(&(_, offset)) => {
+                println!("{} = {}", name, format(state.program, &state.stack[state.fp + offset]));
+            },
+            None => println!("No local variable '{}' in the current frame.", name),
+        }
+    }
+
+    fn prompt(&mut self, state: &State, e: &DebugEntry) {
+        self.show(e);
+        let depth = self.frames.len();
+        let stdin = io::stdin();
+        loop {
+            print!("(qdb) ");
+            io::stdout().flush().ok();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                process::exit(0);
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            match (words[0], words.get(1)) {
+                ("b", Some(arg)) | ("break", Some(arg)) => self.add_breakpoint(arg),
+                ("d", None) | ("delete", None) => self.breakpoints.clear(),
+                ("d", Some(arg)) | ("delete", Some(arg)) => match arg.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= self.breakpoints.len() => { let _ = self.breakpoints.remove(n - 1); },
+                    _ => println!("No breakpoint {}.", arg),
+                },
+                ("i", None) | ("info", None) => self.list_breakpoints(),
+                ("s", None) | ("step", None) => {
+                    self.mode = Mode::StepInto;
+                    return;
+                },
+                ("n", None) | ("next", None) => {
+                    self.mode = Mode::StepOver(depth);
+                    return;
+                },
+                ("f", None) | ("finish", None) => {
+                    if depth == 1 {
+                        println!("'finish' not meaningful in the outermost frame.");
+                    } else {
+                        self.mode = Mode::Finish(depth);
+                        return;
+                    }
+                },
+                ("c", None) | ("continue", None) => {
+                    self.mode = Mode::Continue;
+                    return;
+                },
+                ("l", None) | ("locals", None) => {
+                    if e.locals.is_empty() {
+                        println!("No locals.");
+                    }
+                    for &(id, offset) in e.locals.iter() {
+                        println!("{} = {}", string_table::get(id), format(state.program, &state.stack[state.fp + offset]));
+                    }
+                },
+                ("p", Some(name)) | ("print", Some(name)) => self.print_local(state, e, name),
+                ("bt", None) | ("backtrace", None) => {
+                    for d in (0..depth).rev() {
+                        println!("#{} {}", depth - 1 - d, self.frame_name(d));
+                    }
+                },
+                ("list", None) => self.show(e),
+                ("h", None) | ("help", None) => println!("{}", HELP),
+                ("q", None) | ("quit", None) => process::exit(0),
+                _ => println!("Unknown command '{}'. Type 'help' for a list of commands.", line.trim()),
+            }
+        }
+    }
+}
+
+impl<'s> Monitor for Debugger<'s> {
+    fn instruction(&mut self, state: &State) {
+        let e = match entry(state.program, state.pc) {
+            Some(e) => e,
+            None => return,
+        };
+        let depth = self.frames.len();
+        let mut stop = match self.mode {
+            Mode::Continue => false,
+            Mode::StepInto => true,
+            Mode::StepOver(d) => depth <= d,
+            Mode::Finish(d) => depth < d,
+        };
+        if self.entered {
+            self.entered = false;
+            stop = true;
+        }
+        if let Some(pos) = e.pos {
+            let line = self.line_of(pos);
+            // Don't stop again for another statement on the same line, unless
+            // a jump back, as in a loop, has come round to it again
+            let same = match self.last_line {
+                Some((d, l, pc)) => d == depth && l == line && state.pc > pc,
+                None => false,
+            };
+            if !same {
+                stop = stop || self.breakpoints.iter().any(|bp| match *bp {
+                    Breakpoint::Line(l) => l == line,
+                    _ => false,
+                });
+            }
+            self.last_line = Some((depth, line, state.pc));
+        }
+        if stop {
+            self.mode = Mode::Continue;
+            self.prompt(state, e);
+        }
+    }
+
+    fn enter(&mut self, ft: FunctionToken, state: &State) {
+        let name = state.program.call_table[ft.to_call_index()].name;
+        self.entered = self.breakpoints.iter().any(|bp| match *bp {
+            Breakpoint::Func(f) => Some(f) == name,
+            _ => false,
+        });
+        self.frames.push(name);
+        self.last_line = None;
+    }
+
+    fn leave(&mut self, _state: &State) {
+        let _ = self.frames.pop();
+        self.last_line = None;
+    }
+}
@@ -19,213 +19,583 @@ use util::ops::*;
 use util::string_table;
 
 use backend::bytecode::*;
-use backend::runtime::{self, IRT_TABLE};
+use backend::runtime::{self, Caller, IRT_TABLE};
+use backend::runtime::limits;
+use backend::runtime::observer;
 use backend::runtime::value::Value;
 
 use std::borrow::Borrow;
+use std::mem;
 use std::vec::Vec;
 
-pub fn interpret(program: Program) {
+// A view of the interpreter between instructions, handed to a Monitor.
+pub struct State<'a> {
+    pub program: &'a Program,
+    pub stack: &'a [Value],
+    pub pc: usize,
+    pub fp: usize,
+}
+
+// Observes execution. Every hook has an empty default, so interpreting
+// without a monitor costs nothing.
+pub trait Monitor {
+    // Called before each instruction is executed.
+    fn instruction(&mut self, _state: &State) {}
+    // Called before a builtin runs, with the values it receives: its arguments,
+    // or for an inverse call from reversible code, the result being uncomputed.
+    fn native(&mut self, _ft: FunctionToken, _kind: Call, _args: &[Value], _state: &State) {}
+    // Called once a builtin has finished.
+    fn native_done(&mut self, _ft: FunctionToken) {}
+    // Called once a frame for a Quick function has been set up.
+    fn enter(&mut self, _ft: FunctionToken, _state: &State) {}
+    // Called after returning to the caller's frame.
+    fn leave(&mut self, _state: &State) {}
+}
+
+struct NoMonitor;
+
+impl Monitor for NoMonitor {}
+
+// Bounds on the resources a program may use. Going over any of them stops the
+// program with an error.
+#[derive(Clone, Copy, Default)]
+pub struct Limits {
+    // Instructions executed in total.
+    pub instructions: Option<u64>,
+    // Quick function calls active at once.
+    pub depth: Option<usize>,
+    // Qubits allocated at once, scratch qubits included.
+    pub qubits: Option<usize>,
+    // Values held on the stack and the reversible computation stack.
+    pub stack: Option<usize>,
+}
+
+pub fn interpret(program: Program, limits: &Limits) -> Result<(), String> {
+    interpret_with(program, limits, &mut NoMonitor)
+}
+
+pub fn interpret_with<M: Monitor>(program: Program, limits: &Limits, monitor: &mut M) -> Result<(), String> {
+    limits::set_qubit_limit(limits.qubits);
+    let mut machine = Machine {
+        program: &program,
+        limits: limits,
+        monitor: monitor,
+        fuel: limits.instructions.unwrap_or(u64::max_value()),
+        depth: 0,
+        callbacks: 0,
+    };
     let mut stack: Vec<Value> = Vec::with_capacity(program.call_table[0].locals);
-    let mut aux: Vec<Value> = Vec::new();
     for _ in 0..program.call_table[0].locals {
         stack.push(Value::Null);
     }
-    let mut pc: usize = 0;
-    let mut fp: usize = 0;
-
-    // Top-of-stack optimization
-    let mut a0 = Value::Null;
-
-    loop {
-        //println!("{}: {:?}\n {:?}, {:?}", pc, program.instructions[pc], stack, a0);
-        //println!("{:?}", aux);
-        match program.instructions[pc] {
-            Bytecode::Null => {
-                stack.push(a0);
-                a0 = Value::Null;
-            },
-            Bytecode::Int(v) => {
-                stack.push(a0);
-                a0 = Value::Int(v);
-            },
-            Bytecode::Float(v) => {
-                stack.push(a0);
-                a0 = Value::Float(v);
-            },
-            Bytecode::Bool(v) => {
-                stack.push(a0);
-                a0 = Value::Bool(v);
-            },
-            Bytecode::Func(ft) => {
-                stack.push(a0);
-                a0 = Value::Func(ft);
+    limits::catch(|| machine.run(stack, 0, Value::Null, None, false)).map(|_| ())
+}
+
+// How deeply builtins may call back into Quick code. Each call back starts a
+// run of its own on the native stack, unlike a call made by Quick code, so
+// this is kept well short of what the stack can hold.
+const MAX_CALLBACKS: usize = 200;
+
+// What is shared by every run of the interpreter, including those started by
+// builtins calling back into Quick code.
+struct Machine<'a, M: Monitor + 'a> {
+    program: &'a Program,
+    limits: &'a Limits,
+    monitor: &'a mut M,
+    fuel: u64,
+    depth: usize,
+    // Calls back from builtins active at once.
+    callbacks: usize,
+}
+
+impl<'a, M: Monitor> Caller for Machine<'a, M> {
+    // Runs the function on a stack of its own until it returns. An error is
+    // raised, to stop the program once the builtin which made the call is
+    // done.
+    fn call(&mut self, f: Value, args: Vec<Value>) -> Option<Value> {
+        let (ft, adjoint) = match f {
+            Value::Func(ft) => (ft, false),
+            Value::Adjoint(ft) => (ft, true),
+            _ => limits::fail(format!("Invalid cast of {:?} to Func", f)),
+        };
+        let arity = if ft.is_native() {
+            IRT_TABLE[ft.to_native_index()].arity
+        } else {
+            self.program.call_table[ft.to_call_index()].arity
+        };
+        if arity != args.len() {
+            limits::fail(format!("Function takes {} arguments, but was passed {}", arity, args.len()));
+        }
+        if self.callbacks == MAX_CALLBACKS {
+            limits::fail(format!("Builtins may only call back into Quick code {} calls deep", MAX_CALLBACKS));
+        }
+        self.callbacks += 1;
+        let mut stack = args;
+        let result = if ft.is_native() {
+            let mut aux = Vec::new();
+            self.native(ft, Call::Regular, adjoint, &mut stack, &mut aux, 0, 0)
+        } else {
+            let ref fe = self.program.call_table[ft.to_call_index()];
+            let mut dry_run = None;
+            if adjoint {
+                if fe.ioffset.is_none() {
+                    limits::fail(format!("Function {} is not reversible", function_name(fe)));
+                }
+                observer::set_suppressed(true);
+                dry_run = Some((0, ft, limits::allocated()));
+            }
+            bind_args(&mut stack, 0, fe.arity);
+            for _ in 0..(fe.locals - fe.arity) {
+                stack.push(Value::Null);
+            }
+            stack.push(Value::Addr(0));
+            let depth = self.depth;
+            self.depth += 1;
+            let max_depth = self.limits.depth.unwrap_or(usize::max_value());
+            let result = if self.depth > max_depth {
+                Err(format!("Recursion limit of {} calls exceeded", max_depth))
+            } else {
+                self.monitor.enter(ft, &State { program: self.program, stack: &stack, pc: fe.addr, fp: 0 });
+                self.run(stack, fe.addr, Value::Addr(0), dry_run, true)
+            };
+            self.depth = depth;
+            result
+        };
+        self.callbacks -= 1;
+        match result {
+            Ok(v) => Some(v),
+            Err(err) => {
+                limits::raise(err);
+                None
             },
-            Bytecode::Array(len) => {
-                let mut v = Vec::with_capacity(len);
-                if len != 0 {
-                    let sp = stack.len();
-                    for i in (0..len-1).rev() {
-                        v.push(stack[sp-1-i].clone());
-                    }
-                    v.push(a0);
-                    for _ in 0..len-1 {
-                        let _ = stack.pop().unwrap();
+        }
+    }
+}
+
+impl<'a, M: Monitor> Machine<'a, M> {
+    // Runs from pc in the frame at the bottom of the stack until that frame
+    // returns, giving back its result. A nested run's frame was set up as
+    // for a call; otherwise it is the top level of the program.
+    fn run(&mut self, mut stack: Vec<Value>, mut pc: usize, mut a0: Value,
+           mut dry_run: Option<(usize, FunctionToken, usize)>, nested: bool) -> Result<Value, String> {
+        let program = self.program;
+        let max_depth = self.limits.depth.unwrap_or(usize::max_value());
+        let max_stack = self.limits.stack.unwrap_or(usize::max_value());
+        // Frame of each active ctrl block, innermost last
+        let mut controls: Vec<usize> = Vec::new();
+        // Frame of each reversible function called from regular code, with the
+        // height aux had beforehand, since nothing will undo it
+        let mut forward: Vec<(usize, usize)> = Vec::new();
+
+        let mut aux: Vec<Value> = Vec::new();
+        let mut fp: usize = 0;
+
+        loop {
+            if self.fuel == 0 {
+                return Err(format!("Instruction limit of {} exceeded", self.limits.instructions.unwrap()));
+            }
+            self.fuel -= 1;
+            if stack.len() + aux.len() > max_stack {
+                return Err(format!("Stack limit of {} values exceeded", max_stack));
+            }
+            self.monitor.instruction(&State { program: program, stack: &stack, pc: pc, fp: fp });
+            match program.instructions[pc] {
+                Bytecode::Null => {
+                    stack.push(a0);
+                    a0 = Value::Null;
+                },
+                Bytecode::Int(v) => {
+                    stack.push(a0);
+                    a0 = Value::Int(v);
+                },
+                Bytecode::Float(v) => {
+                    stack.push(a0);
+                    a0 = Value::Float(v);
+                },
+                Bytecode::Bool(v) => {
+                    stack.push(a0);
+                    a0 = Value::Bool(v);
+                },
+                Bytecode::String(s) => {
+                    stack.push(a0);
+                    a0 = Value::String(s);
+                },
+                Bytecode::Func(ft) => {
+                    stack.push(a0);
+                    a0 = Value::Func(ft);
+                },
+                Bytecode::Array(len) => {
+                    let mut v = Vec::with_capacity(len);
+                    if len != 0 {
+                        let sp = stack.len();
+                        for i in (0..len-1).rev() {
+                            v.push(stack[sp-1-i].clone());
+                        }
+                        v.push(a0);
+                        for _ in 0..len-1 {
+                            let _ = stack.pop().unwrap();
+                        }
+                    } else {
+                        stack.push(a0);
                     }
-                } else {
+                    a0 = Value::new_array(v);
+                },
+                Bytecode::Tuple(len) => {
                     stack.push(a0);
-                }
-                a0 = Value::new_array(v);
-            },
-            Bytecode::Op2(kind, op) => {
-                if let Call::Inverse = kind {
-                    let t0 = aux.pop().unwrap();
-                    let t1 = aux.pop().unwrap();
-                    match op {
-                        BinOp::And => a0.iand(t0.clone(), t1.clone()),
-                        BinOp::Or => a0.ior(t0.clone(), t1.clone()),
-                        _ => (),
+                    let sp = stack.len();
+                    let v = stack.drain(sp-len..).collect();
+                    a0 = Value::new_tuple(v);
+                },
+                // Leaves the parts just as Tuple found them, with the last
+                // in a0. Reversible code only takes apart tuples, as its
+                // inverse gathers the parts back into one.
+                Bytecode::Unpack(call, len) => {
+                    let arrays = if let Call::Regular = call { true } else { false };
+                    let mut parts = a0.unpack(len, arrays);
+                    a0 = parts.pop().unwrap();
+                    stack.extend(parts);
+                },
+                // Keys and values alternate, with the last value in a0
+                Bytecode::Map(len) => {
+                    stack.push(a0);
+                    let sp = stack.len();
+                    let entries = {
+                        let mut d = stack.drain(sp-2*len..);
+                        let mut entries = Vec::with_capacity(len);
+                        while let Some(k) = d.next() {
+                            entries.push((k, d.next().unwrap()));
+                        }
+                        entries
+                    };
+                    a0 = Value::new_map(entries);
+                },
+                // The fields come in the order the struct declares them
+                Bytecode::Struct(ref layout) => {
+                    stack.push(a0);
+                    let sp = stack.len();
+                    let fields = stack.drain(sp-layout.fields.len()..).collect();
+                    a0 = Value::new_struct(layout.clone(), fields);
+                },
+                Bytecode::Op2(kind, op) => {
+                    if let Call::Inverse = kind {
+                        let t1 = aux.pop().unwrap();
+                        let t0 = aux.pop().unwrap();
+                        a0.iop2(op, t0.clone(), t1.clone());
+                        a0 = t1;
+                        stack.push(t0);
+                    } else {
+                        let t0 = stack.pop().unwrap();
+                        if let Call::Reverse = kind {
+                            aux.push(t0.clone());
+                            aux.push(a0.clone());
+                        } else if t0.computes_scratch(op, &a0) {
+                            // Nothing would ever clear the scratch qubits
+                            return Err(format!("Operator {:?} on a quantum register may only be used in reversible code, such as the predicate of a with statement", op));
+                        }
+                        a0 = t0.op2(op, a0);
                     }
-                    a0 = t1;
-                    stack.push(t0);
-                } else {
-                    let t0 = stack.pop().unwrap();
-                    if let Call::Reverse = kind {
-                        aux.push(t0.clone());
-                        aux.push(a0.clone());
+                    if let Some(err) = limits::take_error() {
+                        return Err(err);
                     }
-                    match op {
-                        BinOp::Add => a0 = t0.add(a0),
-                        BinOp::Sub => a0 = t0.sub(a0),
-                        BinOp::Mul => a0 = t0.mul(a0),
-                        BinOp::Div => a0 = t0.div(a0),
-                        BinOp::Rem => a0 = t0.rem(a0),
-                        BinOp::Pow => a0 = t0.pow(a0),
-                        BinOp::Lt => a0 = t0.lt(a0),
-                        BinOp::Gt => a0 = t0.gt(a0),
-                        BinOp::Le => a0 = t0.le(a0),
-                        BinOp::Ge => a0 = t0.ge(a0),
-                        BinOp::Eq => a0 = t0.eq(a0),
-                        BinOp::Ne => a0 = t0.ne(a0),
-                        BinOp::And => a0 = t0.and(a0),
-                        BinOp::Or => a0 = t0.or(a0),
-                        BinOp::BAnd => a0 = t0.band(a0),
-                        BinOp::BOr => a0 = t0.bor(a0),
-                        BinOp::BXor => a0 = t0.bxor(a0),
+                },
+                Bytecode::Op1(kind, op) => {
+                    if let Call::Inverse = kind {
+                        let t0 = aux.pop().unwrap();
+                        match op {
+                            UnOp::Not => a0.inot(t0.clone()),
+                            _ => (),
+                        }
+                        a0 = t0;
+                    } else {
+                        if let Call::Reverse = kind {
+                            aux.push(a0.clone());
+                        }
+                        a0 = a0.op1(op);
                     }
-                }
-            },
-            Bytecode::Op1(kind, op) => {
-                if let Call::Inverse = kind {
-                    let t0 = aux.pop().unwrap();
-                    match op {
-                        UnOp::Not => a0.inot(t0.clone()),
-                        _ => (),
+                    if let Some(err) = limits::take_error() {
+                        return Err(err);
                     }
-                    a0 = t0;
-                } else {
-                    if let Call::Reverse = kind {
-                        aux.push(a0.clone());
+                },
+                Bytecode::Call(kind, arity) => {
+                    let callee = if let Call::Inverse = kind {
+                        stack.push(a0);
+                        let al = aux.len();
+                        aux[al-arity-1].clone()
+                    } else {
+                        a0
+                    };
+                    let (ft, adjoint) = match callee {
+                        Value::Func(ft) => (ft, false),
+                        Value::Adjoint(ft) => (ft, true),
+                        _ => return Err(format!("Invalid cast of {:?} to Func", callee)),
+                    };
+                    if adjoint {
+                        if let Call::Regular = kind {} else {
+                            return Err(format!("Adjoint functions may not be called from reversible code"));
+                        }
                     }
-                    match op {
-                        UnOp::Neg => a0 = a0.neg(),
-                        UnOp::Not => a0 = a0.not(),
-                        UnOp::BNot => a0 = a0.bnot(),
+                    let expected = if ft.is_native() {
+                        IRT_TABLE[ft.to_native_index()].arity
+                    } else {
+                        program.call_table[ft.to_call_index()].arity
+                    };
+                    if arity != expected {
+                        return Err(format!("Function takes {} arguments, but was passed {}", expected, arity));
                     }
-                }
-            },
-            Bytecode::Call(kind, arity) => {
-                let ft = if let Call::Inverse = kind {
+                    if ft.is_native() {
+                        a0 = self.native(ft, kind, adjoint, &mut stack, &mut aux, pc, fp)?;
+                    } else {
+                        let ref fe = program.call_table[ft.to_call_index()];
+                        self.depth += 1;
+                        if self.depth > max_depth {
+                            return Err(format!("Recursion limit of {} calls exceeded", max_depth));
+                        }
+                        if stack.len() + aux.len() > max_stack {
+                            return Err(format!("Stack limit of {} values exceeded", max_stack));
+                        }
+                        let old_fp = fp;
+                        match kind {
+                            Call::Inverse => {
+                                // The caller gets back the arguments, as from a
+                                // builtin, with the function left in a0
+                                a0 = stack.pop().unwrap();
+                                let al = aux.len();
+                                stack.extend(aux[al-arity..].iter().cloned());
+                                fp = enter_inverse(fe, &mut stack, &mut aux, pc + 1, old_fp, Value::Func(ft));
+                                pc = fe.addr + fe.ioffset.unwrap();
+                            },
+                            _ => {
+                                for _ in 0..(fe.locals - fe.arity) {
+                                    stack.push(Value::Null);
+                                }
+                                fp = stack.len() - fe.locals;
+                                bind_args(&mut stack, fp, fe.arity);
+                                stack.push(Value::Addr(pc + 1));
+                                a0 = Value::Addr(old_fp);
+                                if adjoint {
+                                    if fe.ioffset.is_none() {
+                                        return Err(format!("Function {} is not reversible", function_name(fe)));
+                                    }
+                                    observer::set_suppressed(true);
+                                    dry_run = Some((fp, ft, limits::allocated()));
+                                } else if let Call::Regular = kind {
+                                    if fe.ioffset.is_some() {
+                                        forward.push((fp, aux.len()));
+                                    }
+                                } else {
+                                    assert!(fe.ioffset.is_some());
+                                }
+                                pc = fe.addr;
+                            },
+                        }
+                        self.monitor.enter(ft, &State { program: program, stack: &stack, pc: pc, fp: fp });
+                        continue;
+                    }
+                },
+                Bytecode::Discard => a0 = stack.pop().unwrap(),
+                Bytecode::Save(n) => {
+                    aux.push(a0);
+                    aux.extend(stack[fp..fp+n].iter().cloned());
+                    a0 = stack.pop().unwrap();
+                },
+                // Moves a value over to aux, for the inverse of a reversible
+                // function to take back
+                Bytecode::Stash => {
+                    aux.push(a0);
+                    a0 = stack.pop().unwrap();
+                },
+                Bytecode::Unstash => {
                     stack.push(a0);
-                    let al = aux.len();
-                    aux[al-arity-1].clone().as_func()
-                } else {
-                    a0.as_func()
-                };
-                if ft.is_native() {
-                    let ref nfe = IRT_TABLE[ft.to_native_index()];
-                    assert_eq!(arity, nfe.arity);
-                    match kind {
-                        Call::Regular => {
-                            (nfe.entry.irr)(&mut stack);
-                            a0 = stack.pop().unwrap();
-                        },
-                        Call::Reverse => {
-                            aux.push(Value::Func(ft));
-                            (nfe.entry.rev)(&mut stack, &mut aux);
-                            a0 = stack.pop().unwrap();
-                        },
-                        Call::Inverse => {
-                            (nfe.entry.inv)(&mut stack, &mut aux);
-                            a0 = aux.pop().unwrap();
-                        },
+                    a0 = aux.pop().unwrap();
+                },
+                Bytecode::Control => {
+                    runtime::push_control(a0.as_qureg());
+                    controls.push(fp);
+                    a0 = stack.pop().unwrap();
+                },
+                Bytecode::Uncontrol => {
+                    runtime::pop_control();
+                    controls.pop();
+                },
+                Bytecode::Return => {
+                    //assert_eq!(aux.len(), 0);
+                    if fp == 0 && !nested { return Ok(a0); }
+                    self.depth -= 1;
+                    let old_fp = fp;
+                    // Returning from within ctrl blocks leaves them
+                    while controls.last() == Some(&old_fp) {
+                        runtime::pop_control();
+                        controls.pop();
                     }
-                } else {
-                    let ref fe = program.call_table[ft.to_call_index()];
-                    assert_eq!(arity, fe.arity);
-                    if fe.ioffset.is_some() {
-                        assert_eq!(fe.locals - fe.arity, 0);
+                    if forward.last().map_or(false, |&(f, _)| f == old_fp) {
+                        let (_, al) = forward.pop().unwrap();
+                        aux.truncate(al);
                     }
-                    for _ in 0..(fe.locals - fe.arity) {
-                        stack.push(Value::Null);
-                    } 
-                    let old_fp = fp;
-                    fp = stack.len() - fe.locals;
-                    stack.push(Value::Addr(pc + 1));
-                    a0 = Value::Addr(old_fp);
-                    match kind {
-                        Call::Regular => {
-                            assert!(fe.ioffset.is_none());
-                            pc = fe.addr;
-                        },
-                        Call::Reverse => {
-                            assert!(fe.ioffset.is_some());
-                            pc = fe.addr;
-                        },
-                        Call::Inverse => {
+                    fp = stack.pop().unwrap().as_addr();
+                    pc = stack.pop().unwrap().as_addr();
+                    stack.truncate(old_fp);
+                    self.monitor.leave(&State { program: program, stack: &stack, pc: pc, fp: fp });
+                    if let Some((dry_fp, ft, qubits)) = dry_run {
+                        if dry_fp == old_fp {
+                            // The forward pass of an adjoint call is done; undo
+                            // it for real, handing its result back to the caller
+                            dry_run = None;
+                            observer::set_suppressed(false);
+                            let ref fe = program.call_table[ft.to_call_index()];
+                            // Scratch qubits holding its result were never
+                            // computed, so the inverse has nothing to undo
+                            if limits::allocated() > qubits {
+                                return Err(format!("Function {} computes its result into scratch qubits, so it has no adjoint", function_name(fe)));
+                            }
+                            self.depth += 1;
+                            let ret = pc;
+                            let old_fp = fp;
+                            fp = enter_inverse(fe, &mut stack, &mut aux, ret, old_fp, a0.clone());
                             pc = fe.addr + fe.ioffset.unwrap();
-                        },
+                            self.monitor.enter(ft, &State { program: program, stack: &stack, pc: pc, fp: fp });
+                            continue;
+                        }
+                    }
+                    if old_fp == 0 {
+                        // The bottom frame of a nested run is done
+                        return Ok(a0);
                     }
                     continue;
+                },
+                // a0 is left sharing the variable's view, as the optimizer
+                // counts on it being the same as what GetLocal would give
+                Bytecode::PutLocal(index) => {
+                    a0 = a0.fork();
+                    stack[fp + index] = a0.clone();
+                },
+                Bytecode::GetLocal(index) => {
+                    stack.push(a0);
+                    a0 = stack[fp + index].clone();
+                },
+                Bytecode::Jump(offset) => {
+                    pc = (pc as isize + offset) as usize;
+                    continue;
+                },
+                Bytecode::Branch(offset) => {
+                    let pred = a0.as_bool();
+                    a0 = stack.pop().unwrap();
+                    if pred {
+                        pc = (pc as isize + offset) as usize;
+                        continue;
+                    }
+                },
+                Bytecode::BranchFalse(offset) => {
+                    let pred = a0.as_bool();
+                    a0 = stack.pop().unwrap();
+                    if !pred {
+                        pc = (pc as isize + offset) as usize;
+                        continue;
+                    }
+                },
+                Bytecode::Print(fmt, nargs) => {
+                    stack.push(a0);
+                    let sp = stack.len();
+                    runtime::printf(string_table::get(fmt).borrow(), &stack[sp-nargs..sp]);
+                    for _ in 0..nargs {
+                        stack.pop().unwrap();
+                    }
+                    a0 = stack.pop().unwrap();
                 }
+            }
+            pc += 1;
+        }
+    }
+
+    // Runs a builtin on the arguments on top of the stack, or for an inverse
+    // call, on the result on top of the stack, giving back what it leaves.
+    fn native(&mut self, ft: FunctionToken, kind: Call, adjoint: bool, stack: &mut Vec<Value>,
+              aux: &mut Vec<Value>, pc: usize, fp: usize) -> Result<Value, String> {
+        let ref nfe = IRT_TABLE[ft.to_native_index()];
+        {
+            let n = if let Call::Inverse = kind { 1 } else { nfe.arity };
+            let sp = stack.len();
+            let state = State { program: self.program, stack: stack, pc: pc, fp: fp };
+            self.monitor.native(ft, if adjoint { Call::Inverse } else { kind }, &stack[sp-n..sp], &state);
+        }
+        let a0 = match kind {
+            Call::Regular if adjoint => {
+                // Run forward without touching any qubits, just to record
+                // what the inverse needs, then run the inverse for real
+                observer::set_suppressed(true);
+                let qubits = limits::allocated();
+                aux.push(Value::Func(ft));
+                (nfe.entry.rev)(stack, aux);
+                observer::set_suppressed(false);
+                if limits::allocated() > qubits {
+                    limits::fail("This builtin computes its result into scratch qubits, so it has no adjoint".to_string());
+                }
+                let result = stack.last().unwrap().clone();
+                (nfe.entry.inv)(stack, aux);
+                let _ = aux.pop().unwrap();
+                for _ in 0..nfe.arity {
+                    let _ = stack.pop().unwrap();
+                }
+                result
             },
-            Bytecode::Discard => a0 = stack.pop().unwrap(),
-            Bytecode::Return => {
-                //assert_eq!(aux.len(), 0);
-                if fp == 0 { return; }
-                let old_fp = fp;
-                fp = stack.pop().unwrap().as_addr();
-                pc = stack.pop().unwrap().as_addr();
-                stack.truncate(old_fp);
-                continue;
-            },
-            Bytecode::PutLocal(index) => stack[fp + index] = a0.clone(),
-            Bytecode::GetLocal(index) => {
-                stack.push(a0);
-                a0 = stack[fp + index].clone();
+            Call::Regular => {
+                (nfe.entry.irr)(stack, self);
+                // A builtin which gave up after an error leaves nothing
+                stack.pop().unwrap_or(Value::Null)
             },
-            Bytecode::Jump(offset) => {
-                pc = (pc as isize + offset) as usize;
-                continue;
+            Call::Reverse => {
+                aux.push(Value::Func(ft));
+                (nfe.entry.rev)(stack, aux);
+                stack.pop().unwrap()
             },
-            Bytecode::Branch(offset) => {
-                let pred = a0.as_bool();
-                a0 = stack.pop().unwrap();
-                if pred {
-                    pc = (pc as isize + offset) as usize;
-                    continue;
-                }
+            Call::Inverse => {
+                (nfe.entry.inv)(stack, aux);
+                aux.pop().unwrap()
             },
-            Bytecode::Print(fmt, nargs) => {
-                stack.push(a0);
-                let sp = stack.len();
-                runtime::printf(string_table::get(fmt).borrow(), &stack[sp-nargs..sp]);
-                for _ in 0..nargs {
-                    stack.pop().unwrap();
-                }
-                a0 = stack.pop().unwrap();
-            }
+        };
+        self.monitor.native_done(ft);
+        if let Some(err) = limits::take_error() {
+            return Err(err);
+        }
+        let max_stack = self.limits.stack.unwrap_or(usize::max_value());
+        if stack.len() + aux.len() > max_stack {
+            return Err(format!("Stack limit of {} values exceeded", max_stack));
         }
-        pc += 1;
+        Ok(a0)
+    }
+}
+
+// Sets up a frame running the inverse of a reversible function, whose
+// arguments are on top of aux with the function just below them. `below` is
+// what the inverse leaves in a0 once it returns. Returns the new frame
+// pointer.
+fn enter_inverse(fe: &FunctionEntry, stack: &mut Vec<Value>, aux: &mut Vec<Value>,
+                 ret: usize, fp: usize, below: Value) -> usize {
+    let al = aux.len();
+    stack.extend(aux.drain(al-fe.arity..));
+    let _ = aux.pop().unwrap();
+    for _ in 0..(fe.locals - fe.arity) {
+        stack.push(Value::Null);
+    }
+    let new_fp = stack.len() - fe.locals;
+    bind_args(stack, new_fp, fe.arity);
+    stack.push(Value::Addr(ret));
+    stack.push(Value::Addr(fp));
+    stack.push(below);
+    new_fp
+}
+
+// Binds the arguments of a new frame to its parameters, which like any other
+// variable get views of their own of the arrays they are passed.
+fn bind_args(stack: &mut Vec<Value>, fp: usize, arity: usize) {
+    for arg in stack[fp..fp + arity].iter_mut() {
+        let v = mem::replace(arg, Value::Null);
+        *arg = v.fork();
+    }
+}
+
+fn function_name(fe: &FunctionEntry) -> String {
+    match fe.name {
+        Some(name) => (*string_table::get(name)).clone(),
+        None => "<top>".to_string(),
     }
 }
@@ -37,7 +37,7 @@ macro_rules! unimplemented {
 #[macro_export]
 macro_rules! builtin_call {
     ($fns:ident, $f:ident, $a:expr) => {{
-        match $fns.lookup(string_table::insert(stringify!($f))) {
+        match $fns.builtin(string_table::insert(stringify!($f))) {
             Some(ft) => $fns.current().func(ft),
             None => return_error!(concat!("Internal error: Undefined function ", stringify!($f))),
         }
@@ -54,7 +54,7 @@ macro_rules! builtin_call {
 #[macro_export]
 macro_rules! builtin_rcall {
     ($fns:ident, $f:ident, $a:expr) => {{
-        match $fns.lookup(string_table::insert(stringify!($f))) {
+        match $fns.builtin(string_table::insert(stringify!($f))) {
             Some(ft) => $fns.current().func(ft),
             None => return_error!(concat!("Internal error: Undefined function ", stringify!($f))),
         }
@@ -21,3 +21,6 @@ mod bytecode;
 mod runtime;
 pub mod compiler;
 pub mod interpreter;
+pub mod debugger;
+pub mod tracer;
+pub mod profiler;
@@ -0,0 +1,246 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use backend::bytecode::*;
+use backend::interpreter::{self, Limits, Monitor, State};
+use backend::runtime::IRT_STRINGS;
+use backend::runtime::observer::{self, GateObserver};
+use backend::runtime::value::Value;
+use util::string_table;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Name of the pseudo-frame for time spent simulating gates in libquantum.
+const SIMULATE: &'static str = "[simulate]";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Function,
+    Builtin,
+    Gate,
+}
+
+struct Frame {
+    kind: Kind,
+    name: String,
+    path: String,
+    start: Instant,
+}
+
+#[derive(Default)]
+struct Stats {
+    calls: u64,
+    instructions: u64,
+    total: Duration,
+    own: Duration,
+    gates: u64,
+    gate_time: Duration,
+}
+
+struct Profile {
+    frames: Vec<Frame>,
+    functions: HashMap<String, Stats>,
+    builtins: HashMap<String, Stats>,
+    folded: HashMap<String, Duration>,
+    instructions: u64,
+    gates: u64,
+    gate_time: Duration,
+    last: Instant,
+}
+
+struct Profiler {
+    profile: Rc<RefCell<Profile>>,
+}
+
+struct GateProfiler {
+    profile: Rc<RefCell<Profile>>,
+}
+
+// Interprets the program, then prints a report of where time was spent to
+// stderr. If `folded` is given, also writes the time spent in each call stack
+// to it, in microseconds, in the folded format used by flamegraph tools. The
+// report is still produced if the program stops with an error.
+pub fn profile(program: Program, folded: Option<Box<Write>>, limits: &Limits) -> Result<(), String> {
+    let now = Instant::now();
+    let profile = Rc::new(RefCell::new(Profile {
+        frames: Vec::new(),
+        functions: HashMap::new(),
+        builtins: HashMap::new(),
+        folded: HashMap::new(),
+        instructions: 0,
+        gates: 0,
+        gate_time: Duration::new(0, 0),
+        last: now,
+    }));
+    profile.borrow_mut().push(Kind::Function, "<top>".to_string());
+    let mut profiler = Profiler { profile: profile.clone() };
+    observer::set_observer(Some(Box::new(GateProfiler { profile: profile.clone() })));
+    let result = interpreter::interpret_with(program, limits, &mut profiler);
+    observer::set_observer(None);
+
+    let mut p = profile.borrow_mut();
+    // Unwind whatever was active when an error stopped the program
+    while !p.frames.is_empty() {
+        p.pop();
+    }
+    p.report(&mut io::stderr());
+    if let Some(mut out) = folded {
+        let mut stacks: Vec<(&String, &Duration)> = p.folded.iter().collect();
+        stacks.sort();
+        for (path, time) in stacks.into_iter() {
+            let _ = writeln!(out, "{} {}", path, micros(*time));
+        }
+        let _ = out.flush();
+    }
+    result
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1_000) as u64
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + d.subsec_nanos() as f64 / 1e6
+}
+
+impl Profile {
+    fn stats(&mut self, kind: Kind, name: &str) -> &mut Stats {
+        let map = if kind == Kind::Builtin { &mut self.builtins } else { &mut self.functions };
+        map.entry(name.to_string()).or_insert_with(Stats::default)
+    }
+
+    // Attributes the time since the last event to the innermost frame.
+    fn charge(&mut self) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        let (kind, name, path) = match self.frames.last() {
+            Some(f) => (f.kind, f.name.clone(), f.path.clone()),
+            None => return,
+        };
+        *self.folded.entry(path).or_insert(Duration::new(0, 0)) += elapsed;
+        if kind == Kind::Gate {
+            self.gate_time += elapsed;
+            // Charge simulation to whatever applied the gate
+            let l = self.frames.len();
+            if l >= 2 {
+                let (kind, name) = (self.frames[l-2].kind, self.frames[l-2].name.clone());
+                self.stats(kind, &name).gate_time += elapsed;
+            }
+        } else {
+            self.stats(kind, &name).own += elapsed;
+        }
+    }
+
+    fn push(&mut self, kind: Kind, name: String) {
+        self.charge();
+        let path = match self.frames.last() {
+            Some(f) => format!("{};{}", f.path, name),
+            None => name.clone(),
+        };
+        if kind != Kind::Gate {
+            self.stats(kind, &name).calls += 1;
+        }
+        self.frames.push(Frame { kind: kind, name: name, path: path, start: self.last });
+    }
+
+    fn pop(&mut self) {
+        self.charge();
+        let f = self.frames.pop().unwrap();
+        // Recursive calls are already covered by the outermost one
+        let outermost = !self.frames.iter().any(|g| g.kind == f.kind && g.name == f.name);
+        if f.kind != Kind::Gate && outermost {
+            let elapsed = self.last - f.start;
+            self.stats(f.kind, &f.name).total += elapsed;
+        }
+    }
+
+    fn report(&self, out: &mut Write) {
+        let total = self.functions.get("<top>").map_or(Duration::new(0, 0), |s| s.total);
+        let _ = writeln!(out, "\n[Profile] {} instructions, {} gates in {:.3} ms ({:.3} ms simulating gates)",
+                         self.instructions, self.gates, millis(total), millis(self.gate_time));
+
+        let mut functions: Vec<(&String, &Stats)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.own.cmp(&a.1.own));
+        let _ = writeln!(out, "\n{:<24} {:>10} {:>12} {:>12} {:>12} {:>12}",
+                         "function", "calls", "instructions", "total ms", "self ms", "gates ms");
+        for (name, s) in functions.into_iter() {
+            let _ = writeln!(out, "{:<24} {:>10} {:>12} {:>12.3} {:>12.3} {:>12.3}",
+                             name, s.calls, s.instructions, millis(s.total), millis(s.own), millis(s.gate_time));
+        }
+
+        let mut builtins: Vec<(&String, &Stats)> = self.builtins.iter().collect();
+        builtins.sort_by(|a, b| (b.1.own + b.1.gate_time).cmp(&(a.1.own + a.1.gate_time)));
+        let _ = writeln!(out, "\n{:<24} {:>10} {:>12} {:>12} {:>12} {:>12}",
+                         "builtin", "calls", "gates", "total ms", "self ms", "gates ms");
+        for (name, s) in builtins.into_iter() {
+            let _ = writeln!(out, "{:<24} {:>10} {:>12} {:>12.3} {:>12.3} {:>12.3}",
+                             name, s.calls, s.gates, millis(s.total), millis(s.own), millis(s.gate_time));
+        }
+    }
+}
+
+impl Monitor for Profiler {
+    fn instruction(&mut self, _state: &State) {
+        let mut p = self.profile.borrow_mut();
+        p.instructions += 1;
+        let name = p.frames.last().unwrap().name.clone();
+        p.stats(Kind::Function, &name).instructions += 1;
+    }
+
+    fn native(&mut self, ft: FunctionToken, _kind: Call, _args: &[Value], _state: &State) {
+        let name = IRT_STRINGS[ft.to_native_index()].to_string();
+        self.profile.borrow_mut().push(Kind::Builtin, name);
+    }
+
+    fn native_done(&mut self, _ft: FunctionToken) {
+        self.profile.borrow_mut().pop();
+    }
+
+    fn enter(&mut self, ft: FunctionToken, state: &State) {
+        let name = match state.program.call_table[ft.to_call_index()].name {
+            Some(name) => (*string_table::get(name)).clone(),
+            None => "<top>".to_string(),
+        };
+        self.profile.borrow_mut().push(Kind::Function, name);
+    }
+
+    fn leave(&mut self, _state: &State) {
+        self.profile.borrow_mut().pop();
+    }
+}
+
+impl GateObserver for GateProfiler {
+    fn gate(&mut self, _gate: &'static str, _qubits: &[usize], _param: Option<f64>) {
+        let mut p = self.profile.borrow_mut();
+        p.gates += 1;
+        let (kind, name) = {
+            let f = p.frames.last().unwrap();
+            (f.kind, f.name.clone())
+        };
+        p.stats(kind, &name).gates += 1;
+        p.push(Kind::Gate, SIMULATE.to_string());
+    }
+
+    fn applied(&mut self, _gate: &'static str) {
+        self.profile.borrow_mut().pop();
+    }
+}
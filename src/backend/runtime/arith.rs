@@ -0,0 +1,567 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Reversible arithmetic on quantum registers, read as unsigned little-endian
+// integers (q[0] is the lowest bit). Everything here works on vectors of
+// single qubits, so that registers can be padded with scratch qubits.
+
+use std::f64::consts::PI;
+use std::vec::Vec;
+
+use backend::runtime::limits;
+use backend::runtime::qureg::{self, QuRegObject};
+use util::ops::BinOp;
+
+// An operand of an operator on quantum registers, where the other operand
+// may be a classical integer.
+pub enum Operand {
+    Quantum(QuRegObject),
+    Classical(i64),
+}
+
+impl Operand {
+    fn len(&self) -> usize {
+        match *self {
+            Operand::Quantum(ref q) => q.len(),
+            Operand::Classical(_) => 0,
+        }
+    }
+}
+
+// Pads bits with fresh scratch qubits up to n bits, returning the scratch
+// qubits added so that they can be released again with release.
+fn pad(anchor: &mut QuRegObject, bits: &mut Vec<QuRegObject>, n: usize) -> Vec<QuRegObject> {
+    let mut scratch = Vec::new();
+    while bits.len() < n {
+        let s = anchor.add_scratch();
+        bits.push(s.clone());
+        scratch.push(s);
+    }
+    scratch
+}
+
+fn release(scratch: Vec<QuRegObject>) {
+    for s in scratch.into_iter().rev() {
+        s.remove_scratch();
+    }
+}
+
+// Cuccaro et al.'s majority gate, leaving the carry out of a position in z.
+fn maj(x: &mut QuRegObject, y: &mut QuRegObject, z: &mut QuRegObject) {
+    y.cnot(z);
+    x.cnot(z);
+    z.toffoli(x, y);
+}
+
+fn imaj(x: &mut QuRegObject, y: &mut QuRegObject, z: &mut QuRegObject) {
+    z.toffoli(x, y);
+    x.cnot(z);
+    y.cnot(z);
+}
+
+// Undoes maj, leaving the sum of a position in y.
+fn uma(x: &mut QuRegObject, y: &mut QuRegObject, z: &mut QuRegObject) {
+    z.toffoli(x, y);
+    x.cnot(z);
+    y.cnot(x);
+}
+
+// b += a, mod 2^n, for n-bit a and b, with a zeroed carry qubit. Runs the
+// carry up through a, then back down, adding into b on the way.
+fn ripple_add(a: &mut [QuRegObject], b: &mut [QuRegObject], carry: &mut QuRegObject) {
+    let n = b.len();
+    maj(carry, &mut b[0], &mut a[0]);
+    for i in 1..n {
+        let (lo, hi) = a.split_at_mut(i);
+        maj(&mut lo[i-1], &mut b[i], &mut hi[0]);
+    }
+    for i in (1..n).rev() {
+        let (lo, hi) = a.split_at_mut(i);
+        uma(&mut lo[i-1], &mut b[i], &mut hi[0]);
+    }
+    uma(carry, &mut b[0], &mut a[0]);
+}
+
+// out ^= (a + b >= 2^n), for n-bit a and b, leaving a and b as they were.
+fn carry_out(a: &mut [QuRegObject], b: &mut [QuRegObject], carry: &mut QuRegObject, out: &mut QuRegObject) {
+    let n = b.len();
+    maj(carry, &mut b[0], &mut a[0]);
+    for i in 1..n {
+        let (lo, hi) = a.split_at_mut(i);
+        maj(&mut lo[i-1], &mut b[i], &mut hi[0]);
+    }
+    out.cnot(&mut a[n-1]);
+    for i in (1..n).rev() {
+        let (lo, hi) = a.split_at_mut(i);
+        imaj(&mut lo[i-1], &mut b[i], &mut hi[0]);
+    }
+    imaj(carry, &mut b[0], &mut a[0]);
+}
+
+// Takes b into the Fourier basis, in which qubit j holds the phase
+// 2 pi b / 2^(j+1), and adding amounts to rotating each qubit. Neither this
+// nor its inverse needs to be controlled, as they cancel out.
+fn fourier(b: &mut [QuRegObject]) {
+    qureg::uncontrolled(|| {
+        for j in (0..b.len()).rev() {
+            let (lo, hi) = b.split_at_mut(j);
+            hi[0].hadamard();
+            for i in 0..j {
+                hi[0].cphaseby(&mut lo[i], PI / (1u64 << (j - i)) as f64);
+            }
+        }
+    });
+}
+
+fn ifourier(b: &mut [QuRegObject]) {
+    qureg::uncontrolled(|| {
+        for j in 0..b.len() {
+            let (lo, hi) = b.split_at_mut(j);
+            for i in (0..j).rev() {
+                hi[0].cphaseby(&mut lo[i], -PI / (1u64 << (j - i)) as f64);
+            }
+            hi[0].hadamard();
+        }
+    });
+}
+
+// b += k, mod 2^n, for classical k.
+fn add_constant(b: &mut [QuRegObject], k: i64) {
+    fourier(b);
+    for j in 0..b.len() {
+        // Only k mod 2^(j+1) matters to qubit j
+        let m = 1i64 << (j + 1);
+        let k = ((k % m) + m) % m;
+        if k != 0 {
+            b[j].phaseby(2.0 * PI * k as f64 / m as f64);
+        }
+    }
+    ifourier(b);
+}
+
+// b += k, mod n, for classical 0 <= k < n and b < n. Uses the extra top bit
+// t to tell whether the sum went past n, and flag f to remember it, after
+// Beauregard.
+fn add_constant_mod(b: &mut Vec<QuRegObject>, k: i64, n: i64, f: &mut QuRegObject) {
+    add_constant(b, k);
+    add_constant(b, -n);
+    f.cnot(b.last_mut().unwrap());
+    qureg::push_control(f.clone());
+    add_constant(b, n);
+    qureg::pop_control();
+    add_constant(b, -k);
+    b.last_mut().unwrap().sigma_x();
+    f.cnot(b.last_mut().unwrap());
+    b.last_mut().unwrap().sigma_x();
+    add_constant(b, k);
+}
+
+// z += c y, mod n, for z < n.
+fn mul_constant_mod(y: &[QuRegObject], z: &mut Vec<QuRegObject>, c: i64, n: i64, f: &mut QuRegObject) {
+    let mut k = c % n;
+    for bit in y.iter() {
+        qureg::push_control(bit.clone());
+        z[0].clone().fold_controls(|| add_constant_mod(z, k, n, f));
+        qureg::pop_control();
+        k = (k * 2) % n;
+    }
+}
+
+// Finds the inverse of c mod n, if there is one.
+fn inverse_mod(c: i64, n: i64) -> Option<i64> {
+    let (mut r0, mut r1) = (n, ((c % n) + n) % n);
+    let (mut t0, mut t1) = (0i64, 1i64);
+    while r1 != 0 {
+        let q = r0 / r1;
+        let (r2, t2) = (r0 - q * r1, t0 - q * t1);
+        r0 = r1;
+        r1 = r2;
+        t0 = t1;
+        t1 = t2;
+    }
+    if r0 != 1 {
+        return None;
+    }
+    Some(((t0 % n) + n) % n)
+}
+
+impl QuRegObject {
+    // self += a, mod 2^len, with a ripple-carry adder.
+    pub fn add_ripple(&mut self, a: &mut QuRegObject) {
+        let n = self.len();
+        let mut anchor = self.clone();
+        self.fold_controls(|| {
+            let mut carry = anchor.add_scratch();
+            let mut abits = a.to_vec();
+            abits.truncate(n);
+            let scratch = pad(&mut anchor, &mut abits, n);
+            ripple_add(&mut abits[..], &mut self.to_vec()[..], &mut carry);
+            release(scratch);
+            carry.remove_scratch();
+        });
+    }
+
+    // self -= a, mod 2^len, as ~(~self + a).
+    pub fn sub_ripple(&mut self, a: &mut QuRegObject) {
+        let mut anchor = self.clone();
+        self.fold_controls(|| {
+            qureg::uncontrolled(|| anchor.sigma_x());
+            anchor.add_ripple(a);
+            qureg::uncontrolled(|| anchor.sigma_x());
+        });
+    }
+
+    // self += sign a, mod 2^len, by rotating self in the Fourier basis.
+    pub fn add_fourier(&mut self, a: &mut QuRegObject, sign: f64) {
+        let mut bits = self.to_vec();
+        let abits = a.to_vec();
+        self.fold_controls(|| {
+            fourier(&mut bits[..]);
+            for j in 0..bits.len() {
+                for i in 0..abits.len().min(j + 1) {
+                    let gamma = sign * PI / (1u64 << (j - i)) as f64;
+                    bits[j].cphaseby(&mut abits[i].clone(), gamma);
+                }
+            }
+            ifourier(&mut bits[..]);
+        });
+    }
+
+    // self += k, mod 2^len.
+    pub fn add_constant(&mut self, k: i64) {
+        let mut bits = self.to_vec();
+        self.fold_controls(|| add_constant(&mut bits[..], k));
+    }
+
+    // Returns a scratch qubit set if self < other.
+    pub fn less_than(&mut self, other: &mut QuRegObject) -> QuRegObject {
+        let mut out = self.add_scratch();
+        self.compare(other, &mut out);
+        out
+    }
+
+    pub fn iless_than(self, orig: &mut QuRegObject, other: &mut QuRegObject) {
+        let mut out = self;
+        orig.compare(other, &mut out);
+        out.remove_scratch();
+    }
+
+    // out ^= (self < other). As ~self + other = 2^n - 1 + (other - self),
+    // that sum carries out exactly when self < other.
+    fn compare(&mut self, other: &mut QuRegObject, out: &mut QuRegObject) {
+        let n = self.len().max(other.len());
+        let mut anchor = self.clone();
+        qureg::uncontrolled(|| {
+            let mut carry = anchor.add_scratch();
+            let mut abits = self.to_vec();
+            let mut bbits = other.to_vec();
+            let ascratch = pad(&mut anchor, &mut abits, n);
+            let bscratch = pad(&mut anchor, &mut bbits, n);
+            for b in abits.iter_mut() {
+                b.sigma_x();
+            }
+            carry_out(&mut abits[..], &mut bbits[..], &mut carry, out);
+            for b in abits.iter_mut() {
+                b.sigma_x();
+            }
+            release(bscratch);
+            release(ascratch);
+            carry.remove_scratch();
+        });
+    }
+
+    // self += a b, mod 2^len, adding b shifted up by i for each set bit i of
+    // a. The factors may not share any qubits.
+    pub fn add_product(&mut self, a: &mut QuRegObject, b: &mut QuRegObject, sign: f64) {
+        let n = self.len();
+        let target = self.clone();
+        self.fold_controls(|| {
+            for (i, bit) in a.to_vec().iter().enumerate().take(n) {
+                target.slice(i, n - i, 1).controlled_by(bit, |p| p.add_fourier(b, sign));
+            }
+        });
+    }
+
+    // self = self g^x, mod n, multiplying by g^(2^i) mod n for each set bit i
+    // of x. Each multiplication computes the product into scratch, swaps it
+    // in, and clears the original using the inverse of the multiplier.
+    //
+    // self must be less than n, which can't be checked without measuring it.
+    // Where it isn't, the original isn't cleared, which is caught as its
+    // scratch qubits are removed.
+    pub fn mul_power_mod(&mut self, x: &mut QuRegObject, g: i64, n: i64) {
+        let width = self.len();
+        // Products of residues must fit in an i64
+        if n < 2 || width > 31 || n > (1i64 << width) {
+            limits::fail(format!("Modulus {} does not fit in a register of {} qubits.", n, width));
+        }
+        let ginv = match inverse_mod(g, n) {
+            Some(v) => v,
+            None => limits::fail(format!("{} has no inverse mod {}.", g, n)),
+        };
+        let mut anchor = self.clone();
+        let mut c = ((g % n) + n) % n;
+        let mut cinv = ginv;
+        for bit in x.to_vec().iter() {
+            qureg::push_control(bit.clone());
+            self.fold_controls(|| {
+                let mut z = Vec::new();
+                let scratch = pad(&mut anchor, &mut z, width + 1);
+                let mut f = anchor.add_scratch();
+                let mut ybits = self.to_vec();
+                mul_constant_mod(&ybits, &mut z, c, n, &mut f);
+                for i in 0..width {
+                    ybits[i].swap(&mut z[i]);
+                }
+                mul_constant_mod(&ybits, &mut z, n - cinv, n, &mut f);
+                f.remove_scratch();
+                release(scratch);
+            });
+            qureg::pop_control();
+            c = c * c % n;
+            cinv = cinv * cinv % n;
+        }
+    }
+
+    // self = self g^-x, mod n, undoing mul_power_mod.
+    pub fn div_power_mod(&mut self, x: &mut QuRegObject, g: i64, n: i64) {
+        match inverse_mod(g, n) {
+            Some(ginv) => self.mul_power_mod(x, ginv, n),
+            None => limits::fail(format!("{} has no inverse mod {}.", g, n)),
+        }
+    }
+}
+
+// Computes a op b into fresh scratch qubits, leaving both operands as they
+// were. Comparisons give a single qubit, and everything else a register as
+// wide as the wider operand, wrapping around as the builtins do.
+pub fn compute(op: BinOp, a: &mut Operand, b: &mut Operand) -> QuRegObject {
+    let mut q = anchor(a, b);
+    let n = a.len().max(b.len());
+    qureg::uncontrolled(|| {
+        let mut s = match op {
+            BinOp::Lt | BinOp::Gt | BinOp::Le | BinOp::Ge | BinOp::Eq | BinOp::Ne => q.add_scratch(),
+            _ => q.add_scratch_register(n),
+        };
+        apply(op, a, b, &mut s, false);
+        s
+    })
+}
+
+// Clears and releases the result of compute.
+pub fn uncompute(s: QuRegObject, op: BinOp, a: &mut Operand, b: &mut Operand) {
+    let mut s = s;
+    qureg::uncontrolled(|| {
+        apply(op, a, b, &mut s, true);
+        s.remove_scratch_register();
+    });
+}
+
+fn anchor(a: &Operand, b: &Operand) -> QuRegObject {
+    match (a, b) {
+        (&Operand::Quantum(ref q), _) | (_, &Operand::Quantum(ref q)) => q.clone(),
+        _ => panic!("Internal Error, found quantum operator with no quantum operand."),
+    }
+}
+
+fn apply(op: BinOp, a: &mut Operand, b: &mut Operand, s: &mut QuRegObject, undo: bool) {
+    match op {
+        BinOp::Add | BinOp::Sub => {
+            let sign = if let BinOp::Add = op { 1 } else { -1 };
+            if undo {
+                add_into(b, s, -sign);
+                xor_into(a, s);
+            } else {
+                xor_into(a, s);
+                add_into(b, s, sign);
+            }
+        },
+        BinOp::Mul => match (a, b) {
+            (&mut Operand::Quantum(ref mut a), &mut Operand::Quantum(ref mut b)) => {
+                s.add_product(a, b, if undo { -1.0 } else { 1.0 });
+            },
+            (&mut Operand::Quantum(ref a), &mut Operand::Classical(k)) |
+            (&mut Operand::Classical(k), &mut Operand::Quantum(ref a)) => {
+                // Adds k shifted up by i for each set bit i of a
+                for (i, bit) in a.to_vec().iter().enumerate() {
+                    let k = k.wrapping_shl(i as u32);
+                    s.controlled_by(bit, |p| p.add_constant(if undo { -k } else { k }));
+                }
+            },
+            _ => panic!("Internal Error, found quantum operator with no quantum operand."),
+        },
+        BinOp::BXor => {
+            xor_into(a, s);
+            xor_into(b, s);
+        },
+        BinOp::BAnd => {
+            let mut sbits = s.to_vec();
+            match (a, b) {
+                (&mut Operand::Quantum(ref a), &mut Operand::Quantum(ref b)) => {
+                    let (mut abits, mut bbits) = (a.to_vec(), b.to_vec());
+                    for i in 0..abits.len().min(bbits.len()) {
+                        sbits[i].toffoli(&mut abits[i], &mut bbits[i]);
+                    }
+                },
+                (&mut Operand::Quantum(ref a), &mut Operand::Classical(k)) |
+                (&mut Operand::Classical(k), &mut Operand::Quantum(ref a)) => {
+                    for (i, bit) in a.to_vec().iter_mut().enumerate() {
+                        if bit_set(k, i) {
+                            sbits[i].cnot(bit);
+                        }
+                    }
+                },
+                _ => panic!("Internal Error, found quantum operator with no quantum operand."),
+            }
+        },
+        BinOp::BOr => {
+            // a | b = a ^ b ^ (a & b)
+            xor_into(a, s);
+            xor_into(b, s);
+            apply(BinOp::BAnd, a, b, s, undo);
+        },
+        BinOp::Eq | BinOp::Ne => {
+            equal_into(a, b, s);
+            if let BinOp::Ne = op {
+                s.sigma_x();
+            }
+        },
+        BinOp::Lt | BinOp::Ge => {
+            less_into(a, b, s);
+            if let BinOp::Ge = op {
+                s.sigma_x();
+            }
+        },
+        BinOp::Gt | BinOp::Le => {
+            less_into(b, a, s);
+            if let BinOp::Le = op {
+                s.sigma_x();
+            }
+        },
+        _ => limits::fail(format!("Operator {:?} not available for quantum registers.", op)),
+    }
+}
+
+fn bit_set(k: i64, i: usize) -> bool {
+    i < 64 && k & (1 << i) != 0
+}
+
+// s ^= a, for a no wider than s.
+fn xor_into(a: &Operand, s: &mut QuRegObject) {
+    let mut sbits = s.to_vec();
+    match *a {
+        Operand::Quantum(ref a) => {
+            for (i, bit) in a.to_vec().iter_mut().enumerate() {
+                sbits[i].cnot(bit);
+            }
+        },
+        Operand::Classical(k) => xor_bits(&mut sbits[..], k),
+    }
+}
+
+// s += sign a, mod 2^len.
+fn add_into(a: &mut Operand, s: &mut QuRegObject, sign: i64) {
+    match *a {
+        Operand::Quantum(ref mut a) if sign > 0 => s.add_ripple(a),
+        Operand::Quantum(ref mut a) => s.sub_ripple(a),
+        Operand::Classical(k) => s.add_constant(k.wrapping_mul(sign)),
+    }
+}
+
+// Flips s if a == b, by checking that a ^ b has no bits set.
+fn equal_into(a: &mut Operand, b: &mut Operand, s: &mut QuRegObject) {
+    match (a, b) {
+        (&mut Operand::Quantum(ref a), &mut Operand::Quantum(ref b)) => {
+            let (mut abits, mut bbits) = (a.to_vec(), b.to_vec());
+            let n = abits.len().min(bbits.len());
+            for i in 0..n {
+                abits[i].cnot(&mut bbits[i]);
+            }
+            let mut bits = abits.clone();
+            bits.extend(bbits[n..].iter().cloned());
+            if_zero(&mut bits, s);
+            for i in 0..n {
+                abits[i].cnot(&mut bbits[i]);
+            }
+        },
+        (&mut Operand::Quantum(ref a), &mut Operand::Classical(k)) |
+        (&mut Operand::Classical(k), &mut Operand::Quantum(ref a)) => {
+            // Only a value which fits in the register can be equal to it
+            let mut bits = a.to_vec();
+            if k < 0 || (bits.len() < 63 && k >= 1 << bits.len()) {
+                return;
+            }
+            xor_bits(&mut bits, k);
+            if_zero(&mut bits, s);
+            xor_bits(&mut bits, k);
+        },
+        _ => panic!("Internal Error, found quantum operator with no quantum operand."),
+    }
+}
+
+// Flips s if none of the bits are set.
+fn if_zero(bits: &mut Vec<QuRegObject>, s: &mut QuRegObject) {
+    xor_bits(bits, !0);
+    for b in bits.iter() {
+        qureg::push_control(b.clone());
+    }
+    s.sigma_x();
+    for _ in bits.iter() {
+        qureg::pop_control();
+    }
+    xor_bits(bits, !0);
+}
+
+fn xor_bits(bits: &mut [QuRegObject], k: i64) {
+    for (i, bit) in bits.iter_mut().enumerate() {
+        if bit_set(k, i) {
+            bit.sigma_x();
+        }
+    }
+}
+
+// Flips s if a < b. A classical operand is written out into scratch qubits
+// for the comparison.
+fn less_into(a: &mut Operand, b: &mut Operand, s: &mut QuRegObject) {
+    match (a, b) {
+        (&mut Operand::Quantum(ref mut a), &mut Operand::Quantum(ref mut b)) => a.compare(b, s),
+        (&mut Operand::Quantum(ref mut a), &mut Operand::Classical(k)) => {
+            // Nothing is less than a negative number
+            if k > 0 {
+                with_constant(a, k, |a, c| a.compare(c, s));
+            }
+        },
+        (&mut Operand::Classical(k), &mut Operand::Quantum(ref mut b)) => {
+            if k < 0 {
+                s.sigma_x();
+            } else {
+                with_constant(b, k, |b, c| c.compare(b, s));
+            }
+        },
+        _ => panic!("Internal Error, found quantum operator with no quantum operand."),
+    }
+}
+
+// Calls f with a scratch register holding k, for k >= 0.
+fn with_constant<F: FnOnce(&mut QuRegObject, &mut QuRegObject)>(q: &mut QuRegObject, k: i64, f: F) {
+    let width = (64 - k.leading_zeros() as usize).max(1);
+    let mut c = q.add_scratch_register(width);
+    xor_into(&Operand::Classical(k), &mut c);
+    f(q, &mut c);
+    xor_into(&Operand::Classical(k), &mut c);
+    c.remove_scratch_register();
+}
@@ -19,68 +19,185 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::vec::Vec;
 
+use backend::runtime::limits;
 use backend::runtime::value::Value;
 
+// A view of every step-th element of an array from start.
+#[derive(Debug)]
+struct View {
+    start: usize,
+    len: usize,
+    step: i64,
+    array: Rc<RefCell<Vec<Value>>>,
+}
+
+impl View {
+    fn index(&self, idx: usize) -> usize {
+        (self.start as i64 + idx as i64 * self.step) as usize
+    }
+
+    fn get(&self, idx: usize) -> Value {
+        if idx >= self.len {
+            limits::fail(format!("Invalid index '{}' into Array.", idx));
+        }
+        self.array.borrow()[self.index(idx)].clone()
+    }
+}
+
+// Copies of an array on the stack share its view, so that a builtin changing
+// it changes the variable or element it was read from. Anywhere an array is
+// stored gets a view of its own by way of fork, sharing only the elements,
+// which are copied before anything changes them while they are shared.
 #[derive(Debug, Clone)]
 pub struct ArrayObject {
-    start: usize,
-    end: usize,
-    array: Rc<RefCell<Vec<Value>>>, 
+    view: Rc<RefCell<View>>,
 }
 
 impl ArrayObject {
     pub fn from_vec(v: Vec<Value>) -> ArrayObject {
+        let v: Vec<Value> = v.into_iter().map(|e| e.fork()).collect();
+        ArrayObject {
+            view: Rc::new(RefCell::new(View {
+                start: 0,
+                len: v.len(),
+                step: 1,
+                array: Rc::new(RefCell::new(v)),
+            })),
+        }
+    }
+
+    // A view of the same elements, to be stored somewhere else.
+    pub fn fork(&self) -> ArrayObject {
+        let view = self.view.borrow();
         ArrayObject {
-            start: 0,
-            end: v.len(),
-            array: Rc::new(RefCell::new(v)),
+            view: Rc::new(RefCell::new(View {
+                start: view.start,
+                len: view.len,
+                step: view.step,
+                array: view.array.clone(),
+            })),
         }
     }
 
     pub fn len(&self) -> usize {
-        self.end - self.start
+        self.view.borrow().len
     }
 
+    // An array held by shared elements is whichever one they all hold, so
+    // changing it would change it for all of them; the elements are copied
+    // first, giving this array one of its own.
     pub fn get(&self, idx: usize) -> Value {
-        if idx > self.end - self.start {
-            panic!("Invalid index '{}' into Array.", idx);
+        let value = self.view.borrow().get(idx);
+        match value {
+            Value::Array(_) if Rc::strong_count(&self.view.borrow().array) != 1 => {
+                self.own();
+                self.view.borrow().get(idx)
+            },
+            _ => value,
         }
-        let start = self.start;
-        self.array.borrow()[start + idx].clone()
     }
 
     pub fn put(&mut self, idx: usize, value: Value) {
-        if idx > self.end - self.start {
-            panic!("Invalid index '{}' into Array.", idx);
+        if idx >= self.len() {
+            limits::fail(format!("Invalid index '{}' into Array.", idx));
         }
-        let start = self.start;
-        self.array.borrow_mut()[start + idx] = value;
+        self.own();
+        let view = self.view.borrow();
+        view.array.borrow_mut()[view.index(idx)] = value.fork();
     }
 
-    pub fn push_front(&mut self, value: Value) {
-        if Rc::strong_count(&self.array) != 1 {
-            panic!("Must only change array size when no views exist.");
+    // Gives the array elements of its own, laid out one after another, so
+    // that it can change without disturbing anything else sharing them.
+    fn own(&self) {
+        let mut view = self.view.borrow_mut();
+        let laid_out = view.start == 0 && view.step == 1 && view.len == view.array.borrow().len();
+        if !laid_out || Rc::strong_count(&view.array) != 1 {
+            let v: Vec<Value> = (0..view.len).map(|i| view.get(i).fork()).collect();
+            view.array = Rc::new(RefCell::new(v));
+            view.start = 0;
+            view.step = 1;
         }
-        self.array.borrow_mut().insert(self.start, value);
-        self.end += 1;
+    }
+
+    // The array itself if nothing else refers to it, otherwise a copy, which
+    // may then change size without the change being seen elsewhere.
+    pub fn unshared(self) -> ArrayObject {
+        if Rc::strong_count(&self.view) == 1 {
+            self
+        } else {
+            self.copy()
+        }
+    }
+
+    pub fn copy(&self) -> ArrayObject {
+        let view = self.view.borrow();
+        ArrayObject::from_vec((0..view.len).map(|i| view.get(i)).collect())
+    }
+
+    pub fn insert(&mut self, idx: usize, value: Value) {
+        if idx > self.len() {
+            limits::fail(format!("Invalid index '{}' into Array.", idx));
+        }
+        self.own();
+        let mut view = self.view.borrow_mut();
+        view.array.borrow_mut().insert(idx, value);
+        view.len += 1;
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Value {
+        if idx >= self.len() {
+            limits::fail(format!("Invalid index '{}' into Array.", idx));
+        }
+        self.own();
+        let mut view = self.view.borrow_mut();
+        view.len -= 1;
+        let value = view.array.borrow_mut().remove(idx);
+        value
+    }
+
+    pub fn push_front(&mut self, value: Value) {
+        self.insert(0, value);
     }
 
     pub fn push_back(&mut self, value: Value) {
-        if Rc::strong_count(&self.array) != 1 {
-            panic!("Must only change array size when no views exist.");
+        let len = self.len();
+        self.insert(len, value);
+    }
+
+    // Reverses the elements in place, after taking a copy of them if they are
+    // shared.
+    pub fn reverse(&mut self) {
+        self.own();
+        let len = self.len();
+        for i in 0..len / 2 {
+            let a = self.get(i);
+            let b = self.get(len - i - 1);
+            self.put(i, b);
+            self.put(len - i - 1, a);
         }
-        self.array.borrow_mut().insert(self.end, value);
-        self.end += 1;
     }
 
-    pub fn slice(&self, lb: usize, ub: usize) -> Value {
-        if lb >= ub || ub > self.end - self.start {
-            panic!("Invalid slice indicies '{}:{}' into Array.", lb, ub);
+    // Sorts the elements in place, taking a copy of them first just as
+    // reverse does.
+    pub fn sort(&mut self) {
+        self.own();
+        let mut v: Vec<Value> = (0..self.len()).map(|i| self.get(i)).collect();
+        v.sort_by(|a, b| a.compare(b));
+        for (i, e) in v.into_iter().enumerate() {
+            self.put(i, e);
         }
+    }
+
+    // Takes len elements, every step-th one from start.
+    pub fn slice(&self, start: usize, len: usize, step: i64) -> Value {
+        let view = self.view.borrow();
         Value::Array(ArrayObject {
-            start: self.start + lb,
-            end: self.start + ub,
-            array: self.array.clone(),
+            view: Rc::new(RefCell::new(View {
+                start: if len == 0 { 0 } else { view.index(start) },
+                len: len,
+                step: view.step * step,
+                array: view.array.clone(),
+            })),
         })
     }
 
@@ -88,10 +205,9 @@ impl ArrayObject {
         let mut out = String::new();
         out.push('[');
         out.push(' ');
-        let start = self.start;
-        let end = self.end;
-        for i in self.array.borrow()[start..end].iter() {
-            out.push_str(&(i.clone().as_string())[..]);
+        let view = self.view.borrow();
+        for i in 0..view.len {
+            out.push_str(&(view.get(i).as_string())[..]);
             out.push(' ');
         }
         out.push(']');
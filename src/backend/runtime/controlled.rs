@@ -0,0 +1,100 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Singly controlled forms of the primitive single-qubit gates, built out of
+// the gates libquantum provides. Each takes the raw indices of the control
+// and target qubits.
+
+use libquantum::QuReg;
+
+use backend::runtime::observer;
+
+use std::f64::consts::PI;
+
+fn cnot(qm: &mut QuReg, c: usize, t: usize) {
+    observer::apply("cnot", &[c, t], None, || qm.cnot(c, t));
+}
+
+fn hadamard_t(qm: &mut QuReg, t: usize) {
+    observer::apply("hadamard", &[t], None, || qm.hadamard(t));
+}
+
+fn rotate_y_t(qm: &mut QuReg, t: usize, gamma: f64) {
+    observer::apply("rotate_y", &[t], Some(gamma), || qm.rotate_y(t, gamma as f32));
+}
+
+fn rotate_z_t(qm: &mut QuReg, t: usize, gamma: f64) {
+    observer::apply("rotate_z", &[t], Some(gamma), || qm.rotate_z(t, gamma as f32));
+}
+
+fn phaseby_t(qm: &mut QuReg, t: usize, gamma: f64) {
+    observer::apply("phaseby", &[t], Some(gamma), || qm.phaseby(t, gamma as f32));
+}
+
+// H = Ry(-pi/4) X Ry(pi/4)
+pub fn hadamard(qm: &mut QuReg, c: usize, t: usize) {
+    rotate_y_t(qm, t, PI / 4.0);
+    cnot(qm, c, t);
+    rotate_y_t(qm, t, -PI / 4.0);
+}
+
+pub fn sigma_x(qm: &mut QuReg, c: usize, t: usize) {
+    cnot(qm, c, t);
+}
+
+// Y = S X S*
+pub fn sigma_y(qm: &mut QuReg, c: usize, t: usize) {
+    phaseby_t(qm, t, -PI / 2.0);
+    cnot(qm, c, t);
+    phaseby_t(qm, t, PI / 2.0);
+}
+
+pub fn sigma_z(qm: &mut QuReg, c: usize, t: usize) {
+    phaseby(qm, c, t, PI);
+}
+
+pub fn rotate_x(qm: &mut QuReg, c: usize, t: usize, gamma: f64) {
+    hadamard_t(qm, t);
+    rotate_z(qm, c, t, gamma);
+    hadamard_t(qm, t);
+}
+
+// Conjugating by X negates the angle, so the halves cancel unless the
+// control is set.
+pub fn rotate_y(qm: &mut QuReg, c: usize, t: usize, gamma: f64) {
+    rotate_y_t(qm, t, gamma / 2.0);
+    cnot(qm, c, t);
+    rotate_y_t(qm, t, -gamma / 2.0);
+    cnot(qm, c, t);
+}
+
+pub fn rotate_z(qm: &mut QuReg, c: usize, t: usize, gamma: f64) {
+    rotate_z_t(qm, t, gamma / 2.0);
+    cnot(qm, c, t);
+    rotate_z_t(qm, t, -gamma / 2.0);
+    cnot(qm, c, t);
+}
+
+// `phase` scales the whole state, which under a control becomes a phase on
+// the control itself.
+pub fn phase(qm: &mut QuReg, c: usize, _t: usize, gamma: f64) {
+    phaseby_t(qm, c, gamma);
+}
+
+pub fn phaseby(qm: &mut QuReg, c: usize, t: usize, gamma: f64) {
+    observer::apply("cond_phaseby", &[c, t], Some(gamma), || qm.cond_phaseby(c, t, gamma as f32));
+}
@@ -0,0 +1,98 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Builtins have no way to return an error, so one which needs to stop the
+// program raises it here instead; the interpreter checks for it after every
+// builtin call and operator. One which can't carry on at all, such as on
+// being given a value of the wrong type, fails instead.
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self, AssertUnwindSafe};
+
+thread_local!(static ERROR: RefCell<Option<String>> = RefCell::new(None));
+thread_local!(static QUBIT_LIMIT: Cell<Option<usize>> = Cell::new(None));
+thread_local!(static QUBITS: Cell<usize> = Cell::new(0));
+
+pub fn raise(err: String) {
+    ERROR.with(|e| {
+        let mut e = e.borrow_mut();
+        // Keep the first error, since later ones are likely just fallout
+        if e.is_none() {
+            *e = Some(err);
+        }
+    });
+}
+
+pub fn take_error() -> Option<String> {
+    ERROR.with(|e| e.borrow_mut().take())
+}
+
+// Unwinds out of a failed operation, once its error has been raised.
+struct Failed;
+
+// Raises an error and abandons whatever ran into it, unwinding back to the
+// enclosing call of catch.
+pub fn fail(err: String) -> ! {
+    raise(err);
+    panic::resume_unwind(Box::new(Failed))
+}
+
+// Runs `f`, turning a failure within it into the error it raised. Any other
+// panic is passed on.
+pub fn catch<T, F: FnOnce() -> Result<T, String>>(f: F) -> Result<T, String> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            if !payload.is::<Failed>() {
+                panic::resume_unwind(payload);
+            }
+            Err(take_error().unwrap())
+        },
+    }
+}
+
+// Limits the number of qubits, scratch qubits included, which may be
+// allocated at any one time.
+pub fn set_qubit_limit(limit: Option<usize>) {
+    QUBIT_LIMIT.with(|l| l.set(limit));
+}
+
+// Counts n more qubits as allocated. If that goes over the limit, raises an
+// error and returns false.
+pub fn reserve(n: usize) -> bool {
+    let total = QUBITS.with(|q| {
+        let total = q.get() + n;
+        q.set(total);
+        total
+    });
+    match QUBIT_LIMIT.with(|l| l.get()) {
+        Some(limit) if total > limit => {
+            raise(format!("Qubit limit of {} exceeded", limit));
+            false
+        },
+        _ => true,
+    }
+}
+
+// The number of qubits allocated at the moment.
+pub fn allocated() -> usize {
+    QUBITS.with(|q| q.get())
+}
+
+pub fn release(n: usize) {
+    QUBITS.with(|q| q.set(q.get().saturating_sub(n)));
+}
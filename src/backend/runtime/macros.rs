@@ -24,25 +24,65 @@ macro_rules! invalid_rcall {
 }
 
 macro_rules! irt_entry {
-    ($f:ident, $s:ident, $rs:ident, { (regular) = $n:block (reverse) = $r:block (inverse) = $i:block }) => {
+    ($f:ident, $a:expr, $s:ident, $rs:ident, { (regular) = $n:block (reverse) = $r:block (inverse) = $i:block }) => {
         IRTEntry {
-            irr: &|$s| $n,
+            irr: &|$s, _| $n,
             rev: &|$s, $rs| $r,
             inv: &|$s, $rs| $i,
+            reversible: true,
         }
     };
-    ($f:ident, $s:ident, $rs:ident, { (reverse) = $r:block (inverse) = $i:block }) => {
+    // Builtins which are their own inverse.
+    ($f:ident, $a:expr, $s:ident, $rs:ident, { (self_inverse) = $b:block }) => {
+        irt_entry!($f, $a, $s, $rs, { (apply) = $b (unapply) = $b })
+    };
+    // Builtins undone by another operation on the same arguments, which
+    // takes and returns values just as the builtin does. The arguments are
+    // saved on aux so that the inverse can apply the other operation to them.
+    ($f:ident, $a:expr, $s:ident, $rs:ident, { (apply) = $b:block (unapply) = $u:block }) => {
+        IRTEntry {
+            irr: &|$s, _| $b,
+            rev: &|$s, $rs| {
+                let sl_ = $s.len();
+                $rs.extend($s[sl_-$a..].iter().cloned());
+                $b
+            },
+            inv: &|$s, $rs| {
+                let _ = $s.pop().unwrap();
+                let al_ = $rs.len();
+                let args_: Vec<Value> = $rs.drain(al_-$a..).collect();
+                $s.extend(args_.iter().cloned());
+                $u
+                let _ = $s.pop().unwrap();
+                $s.extend(args_);
+            },
+            reversible: true,
+        }
+    };
+    ($f:ident, $a:expr, $s:ident, $rs:ident, { (reverse) = $r:block (inverse) = $i:block }) => {
         IRTEntry {
-            irr: &|_| invalid_call!($f),
+            irr: &|_, _| invalid_call!($f),
             rev: &|$s, $rs| $r,
             inv: &|$s, $rs| $i,
+            reversible: true,
         }
     };
-    ($f:ident, $s:ident, $rs:ident, $b:block) => {
+    // Builtins which call back into Quick code, taking a Caller in place
+    // of aux.
+    ($f:ident, $a:expr, $s:ident, $rs:ident, { (calls) = $b:block }) => {
         IRTEntry {
-            irr: &|$s| $b,
+            irr: &|$s, $rs| $b,
             rev: &|_, _| invalid_rcall!($f),
             inv: &|_, _| invalid_rcall!($f),
+            reversible: false,
+        }
+    };
+    ($f:ident, $a:expr, $s:ident, $rs:ident, $b:block) => {
+        IRTEntry {
+            irr: &|$s, _| $b,
+            rev: &|_, _| invalid_rcall!($f),
+            inv: &|_, _| invalid_rcall!($f),
+            reversible: false,
         }
     };
 }
@@ -54,11 +94,23 @@ macro_rules! irt_table {
             $(stringify!($i)),*
         ];
         pub const IRT_TABLE: &'static [IRTFunction] = &[
-            $(IRTFunction { entry: irt_entry!($i, $s, $rs, $t), arity: $n }),* 
+            $(IRTFunction { entry: irt_entry!($i, $n, $s, $rs, $t), arity: $n }),* 
         ];
     }
 }
 
+// Calls back into Quick code from a builtin, which gives up if the program
+// has been stopped by an error.
+#[macro_export]
+macro_rules! call_back {
+    ($c:ident, $f:expr, $($a:expr),*) => {
+        match $c.call($f.clone(), vec![$($a),*]) {
+            Some(r_) => r_,
+            None => return,
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! simple_irt_fn {
     ($stack:ident, $f:ident) => {
@@ -134,7 +186,7 @@ macro_rules! math_irt_fn {
         $stack.push(match s {
             Value::Int(v) => Value::Float((v as f64).$f()),
             Value::Float(v) => Value::Float(v.$f()),
-            _ => panic!("sqrt only defined for Int and Float."),
+            _ => limits::fail(concat!(stringify!($f), " only defined for Int and Float.").to_string()),
         });
     }
 }
@@ -143,11 +195,13 @@ macro_rules! math_irt_fn {
 macro_rules! qureg_fn_t {
     ($f:ident) => {
         pub fn $f(&mut self) {
-            let start = self.raw_start();
-            let end = self.raw_end();
+            if self.controlled(|qm, c, i| controlled::$f(qm, c, i)) {
+                return;
+            }
+            let targets = self.qubits();
             let mut qm = self.qureg.borrow_mut();
-            for i in start..end {
-                qm.$f(i);
+            for &i in targets.iter() {
+                observer::apply(stringify!($f), &[i], None, || qm.$f(i));
             }
         }
     }
@@ -157,11 +211,13 @@ macro_rules! qureg_fn_t {
 macro_rules! qureg_fn_t_g {
     ($f:ident) => {
         pub fn $f(&mut self, gamma: f64) {
-            let start = self.raw_start();
-            let end = self.raw_end();
+            if self.controlled(|qm, c, i| controlled::$f(qm, c, i, gamma)) {
+                return;
+            }
+            let targets = self.qubits();
             let mut qm = self.qureg.borrow_mut();
-            for i in start..end {
-                qm.$f(i, gamma as f32);
+            for &i in targets.iter() {
+                observer::apply(stringify!($f), &[i], Some(gamma), || qm.$f(i, gamma as f32));
             }
         }
     }
@@ -173,7 +229,7 @@ macro_rules! qureg_irt_fn_t {
         let s = $stack.pop().unwrap();
         match s {
             Value::QuReg(mut q) => { q.$f(); $stack.push(Value::QuReg(q)); },
-            _ => panic!(concat!(stringify!($f), " only available on quantum registers and bits.")),
+            _ => limits::fail(concat!(stringify!($f), " only available on quantum registers and bits.").to_string()),
         }
     }
 }
@@ -185,7 +241,7 @@ macro_rules! qureg_irt_rev_fn_t {
         $aux.push(s.clone());
         match s {
             Value::QuReg(mut q) => { q.$f(); $stack.push(Value::QuReg(q)); },
-            _ => panic!(concat!(stringify!($f), " only available on quantum registers and bits.")),
+            _ => limits::fail(concat!(stringify!($f), " only available on quantum registers and bits.").to_string()),
         }
     }
 }
@@ -196,7 +252,7 @@ macro_rules! qureg_irt_inv_fn_t {
         let s = $stack.pop().unwrap();
         match s {
             Value::QuReg(mut q) => q.$f(),
-            _ => panic!(concat!(stringify!($f), " only available on quantum registers and bits.")),
+            _ => limits::fail(concat!(stringify!($f), " only available on quantum registers and bits.").to_string()),
         }
         $stack.push($aux.pop().unwrap());
     }
@@ -209,7 +265,7 @@ macro_rules! qureg_irt_fn_t_g {
         let s = $stack.pop().unwrap(); 
         match s {
             Value::QuReg(mut q) => { q.$f(g.as_float()); $stack.push(Value::QuReg(q)); },
-            _ => panic!(concat!(stringify!($f), " only available on quantum registers and bits.")),
+            _ => limits::fail(concat!(stringify!($f), " only available on quantum registers and bits.").to_string()),
         }
     }
 }
@@ -226,7 +282,7 @@ macro_rules! qureg_irt_rev_fn_t_g {
                 q.$f(g.as_float());
                 $stack.push(Value::QuReg(q));
             },
-            _ => panic!(concat!(stringify!($f), " only available on quantum registers and bits.")),
+            _ => limits::fail(concat!(stringify!($f), " only available on quantum registers and bits.").to_string()),
         }
     }
 }
@@ -234,13 +290,53 @@ macro_rules! qureg_irt_rev_fn_t_g {
 #[macro_export]
 macro_rules! qureg_irt_inv_fn_t_g {
     ($stack:ident, $aux:ident, $f:ident) => {
+        let _ = $stack.pop().unwrap();
         let g = $aux.pop().unwrap();
-        let s = $stack.pop().unwrap();
+        let s = $aux.pop().unwrap();
         $stack.push(s.clone());
         $stack.push(g.clone());
         match s {
-            Value::QuReg(mut q) => { q.$f(-g.as_float()); $stack.push(Value::QuReg(q)); },
-            _ => panic!(concat!(stringify!($f), " only available on quantum registers and bits.")),
+            Value::QuReg(mut q) => q.$f(-g.as_float()),
+            _ => limits::fail(concat!(stringify!($f), " only available on quantum registers and bits.").to_string()),
         }
     }
 }
+
+#[macro_export]
+macro_rules! qureg_irt_fn_c_t_g {
+    ($stack:ident, $f:ident) => {
+        let g = $stack.pop().unwrap().as_float();
+        let mut t = $stack.pop().unwrap().as_qureg();
+        let mut c = $stack.pop().unwrap().as_qureg();
+        t.$f(&mut c, g);
+        $stack.push(Value::QuReg(t));
+    }
+}
+
+#[macro_export]
+macro_rules! qureg_irt_rev_fn_c_t_g {
+    ($stack:ident, $aux:ident, $f:ident) => {
+        let g = $stack.pop().unwrap();
+        let mut t = $stack.pop().unwrap().as_qureg();
+        let c = $stack.pop().unwrap();
+        $aux.push(c.clone());
+        $aux.push(Value::QuReg(t.clone()));
+        $aux.push(g.clone());
+        t.$f(&mut c.as_qureg(), g.as_float());
+        $stack.push(Value::QuReg(t));
+    }
+}
+
+#[macro_export]
+macro_rules! qureg_irt_inv_fn_c_t_g {
+    ($stack:ident, $aux:ident, $f:ident) => {
+        let _ = $stack.pop().unwrap();
+        let g = $aux.pop().unwrap();
+        let t = $aux.pop().unwrap();
+        let c = $aux.pop().unwrap();
+        t.clone().as_qureg().$f(&mut c.clone().as_qureg(), -g.clone().as_float());
+        $stack.push(c);
+        $stack.push(t);
+        $stack.push(g);
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use backend::runtime::limits;
+use backend::runtime::value::Value;
+use util::string_table::{self, StringToken};
+
+// The values a map may be keyed by. Keys of different kinds are ordered Bools
+// first, then Ints, then Strings, which are ordered by their text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Bool(bool),
+    Int(i64),
+    String(StringToken),
+}
+
+impl Key {
+    fn from_value(v: Value) -> Key {
+        match v {
+            Value::Bool(b) => Key::Bool(b),
+            Value::Int(i) => Key::Int(i),
+            Value::String(s) => Key::String(s),
+            _ => limits::fail(format!("Map keys must be Ints, Bools or Strings, not {}.", v.kind())),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        match *self {
+            Key::Bool(b) => Value::Bool(b),
+            Key::Int(i) => Value::Int(i),
+            Key::String(s) => Value::String(s),
+        }
+    }
+
+    fn rank(&self) -> usize {
+        match *self {
+            Key::Bool(_) => 0,
+            Key::Int(_) => 1,
+            Key::String(_) => 2,
+        }
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        match (*self, *other) {
+            (Key::Bool(a), Key::Bool(b)) => a.cmp(&b),
+            (Key::Int(a), Key::Int(b)) => a.cmp(&b),
+            (Key::String(a), Key::String(b)) => string_table::get(a).cmp(&string_table::get(b)),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// A map kept in the order of its keys. Like an array, copies of a map share
+// it, so that changes made through one are seen by all.
+#[derive(Debug, Clone)]
+pub struct MapObject {
+    map: Rc<RefCell<BTreeMap<Key, Value>>>,
+}
+
+impl MapObject {
+    pub fn from_vec(entries: Vec<(Value, Value)>) -> MapObject {
+        let mut map = BTreeMap::new();
+        for (k, v) in entries.into_iter() {
+            map.insert(Key::from_value(k), v.fork());
+        }
+        MapObject {
+            map: Rc::new(RefCell::new(map)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.borrow().len()
+    }
+
+    pub fn get(&self, key: Value) -> Value {
+        match self.map.borrow().get(&Key::from_value(key.clone())) {
+            Some(v) => v.clone(),
+            None => limits::fail(format!("Key {} not found in Map.", key.as_string())),
+        }
+    }
+
+    pub fn put(&mut self, key: Value, value: Value) {
+        self.map.borrow_mut().insert(Key::from_value(key), value.fork());
+    }
+
+    pub fn has(&self, key: Value) -> bool {
+        self.map.borrow().contains_key(&Key::from_value(key))
+    }
+
+    pub fn remove(&mut self, key: Value) -> Value {
+        match self.map.borrow_mut().remove(&Key::from_value(key.clone())) {
+            Some(v) => v,
+            None => limits::fail(format!("Key {} not found in Map.", key.as_string())),
+        }
+    }
+
+    pub fn keys(&self) -> Vec<Value> {
+        self.map.borrow().keys().map(|k| k.to_value()).collect()
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        self.map.borrow().values().cloned().collect()
+    }
+
+    pub fn copy(&self) -> MapObject {
+        MapObject {
+            map: Rc::new(RefCell::new(self.map.borrow().iter().map(|(k, v)| (*k, v.clone().fork())).collect())),
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        let entries: Vec<String> = self.map.borrow().iter().map(|(k, v)| {
+            format!("{}: {}", k.to_value().as_string(), v.clone().as_string())
+        }).collect();
+        if entries.is_empty() {
+            "{ }".to_string()
+        } else {
+            format!("{{ {} }}", entries.join(", "))
+        }
+    }
+}
@@ -18,8 +18,16 @@
 #[macro_use]
 mod macros;
 mod array;
+mod map;
 mod qureg;
+mod controlled;
+mod unitary;
+mod arith;
 pub mod value;
+pub mod structure;
+pub mod observer;
+pub mod limits;
 mod runtime;
 
 pub use backend::runtime::runtime::*;
+pub use backend::runtime::qureg::{push_control, pop_control};
@@ -0,0 +1,73 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::{Cell, RefCell};
+
+// Receives every primitive operation QuRegObject hands to libquantum, along
+// with the raw qubit indices it is applied to.
+pub trait GateObserver {
+    // Called before the operation is simulated.
+    fn gate(&mut self, name: &'static str, qubits: &[usize], param: Option<f64>);
+    // Called once the operation has been simulated.
+    fn applied(&mut self, _name: &'static str) {}
+}
+
+thread_local!(static OBSERVER: RefCell<Option<Box<GateObserver>>> = RefCell::new(None));
+thread_local!(static SUPPRESSED: Cell<bool> = Cell::new(false));
+
+pub fn set_observer(observer: Option<Box<GateObserver>>) {
+    OBSERVER.with(|o| *o.borrow_mut() = observer);
+}
+
+// While suppressed, operations are skipped entirely, so that reversible code
+// can be run just to record what its inverse needs to know.
+pub fn set_suppressed(suppressed: bool) {
+    SUPPRESSED.with(|s| s.set(suppressed));
+}
+
+pub fn suppressed() -> bool {
+    SUPPRESSED.with(|s| s.get())
+}
+
+// Performs a libquantum operation, reporting it to the observer if there is
+// one. A suppressed operation yields the default value, e.g. a measurement of
+// zero.
+pub fn apply<T: Default, F: FnOnce() -> T>(name: &'static str, qubits: &[usize], param: Option<f64>, op: F) -> T {
+    if SUPPRESSED.with(|s| s.get()) {
+        return T::default();
+    }
+    // The observer is not borrowed while the operation runs, as it may
+    // apply operations of its own
+    let observed = OBSERVER.with(|o| {
+        match *o.borrow_mut() {
+            Some(ref mut observer) => {
+                observer.gate(name, qubits, param);
+                true
+            },
+            None => false,
+        }
+    });
+    let result = op();
+    if observed {
+        OBSERVER.with(|o| {
+            if let Some(ref mut observer) = *o.borrow_mut() {
+                observer.applied(name);
+            }
+        });
+    }
+    result
+}
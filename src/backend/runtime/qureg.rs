@@ -16,44 +16,104 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use std::cell::RefCell;
+use std::f64::consts::PI;
+use std::mem;
 use std::rc::Rc;
 
 use libquantum::QuReg;
 
+use backend::runtime::controlled;
+use backend::runtime::limits;
+use backend::runtime::observer;
+use backend::runtime::unitary::{self, Matrix, TwoLevel};
+
+// A qubit of an allocated register. Scratch qubits are numbered in the order
+// they were added, and are kept apart from the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Qubit {
+    Data(usize),
+    Scratch(usize),
+}
+
+// A view of any of the qubits of an allocated register, in any order, with
+// bits[0] as its lowest qubit.
 #[derive(Debug, Clone)]
 pub struct QuRegObject {
-    start: usize,
-    end: usize,
-    scratch: bool,
+    bits: Vec<Qubit>,
     qureg: Rc<RefCell<QuReg>>, 
 }
 
-impl QuRegObject {
-    pub fn new(s: usize, init: i64) -> QuRegObject {
-        QuRegObject {
-            start: 0,
-            end: s,
-            scratch: false,
-            qureg: Rc::new(RefCell::new(QuReg::new(s, init as u64))),
+thread_local!(static CONTROLS: RefCell<Vec<QuRegObject>> = RefCell::new(Vec::new()));
+
+// Makes every gate applied until the matching pop_control conditional on all
+// the qubits of `control` being set.
+pub fn push_control(control: QuRegObject) {
+    CONTROLS.with(|c| c.borrow_mut().push(control));
+}
+
+pub fn pop_control() {
+    CONTROLS.with(|c| { let _ = c.borrow_mut().pop(); });
+}
+
+// Takes the active controls, so that the gates making up a controlled gate
+// aren't controlled themselves. They must be handed back with
+// restore_controls.
+fn take_controls() -> Vec<QuRegObject> {
+    CONTROLS.with(|c| mem::replace(&mut *c.borrow_mut(), Vec::new()))
+}
+
+fn restore_controls(controls: Vec<QuRegObject>) {
+    CONTROLS.with(|c| *c.borrow_mut() = controls);
+}
+
+// Runs `f` with no active controls. Values computed into scratch qubits are
+// uncomputed before the scratch is released, so they needn't be controlled.
+pub fn uncontrolled<T, F: FnOnce() -> T>(f: F) -> T {
+    let controls = take_controls();
+    let result = f();
+    restore_controls(controls);
+    result
+}
+
+impl Drop for QuRegObject {
+    fn drop(&mut self) {
+        // The last view of a register frees it
+        if Rc::strong_count(&self.qureg) == 1 {
+            let qureg = self.qureg.borrow();
+            limits::release(qureg.width() - qureg.scratch());
         }
     }
+}
 
-    fn raw_start(&self) -> usize {
-        if self.scratch {
-            self.scratch() - self.start - 1
-        } else {
-            self.start + self.scratch()
+impl QuRegObject {
+    // Returns None, having raised an error, if this would go over the qubit
+    // limit.
+    pub fn new(s: usize, init: i64) -> Option<QuRegObject> {
+        if !limits::reserve(s) {
+            limits::release(s);
+            return None;
         }
+        Some(QuRegObject {
+            bits: (0..s).map(Qubit::Data).collect(),
+            qureg: Rc::new(RefCell::new(QuReg::new(s, init as u64))),
+        })
     }
 
-    fn raw_end(&self) -> usize {
-        if self.scratch {
-            self.scratch() - self.end + 1
-        } else {
-            self.end + self.scratch()
+    // Scratch qubits are added below the others, so each one moves up as
+    // more are added.
+    fn raw(&self, bit: Qubit) -> usize {
+        match bit {
+            Qubit::Data(i) => i + self.scratch(),
+            Qubit::Scratch(i) => self.scratch() - i - 1,
         }
     }
 
+    // The raw index of a single qubit.
+    fn raw_bit(&self) -> usize {
+        assert!(self.qubit());
+        self.raw(self.bits[0])
+    }
+
     fn qubit(&self) -> bool {
         self.len() == 1
     }
@@ -62,53 +122,100 @@ impl QuRegObject {
         self.qureg.borrow().scratch()
     }
 
-    fn add_scratch(&mut self) -> QuRegObject {
+    pub fn add_scratch(&mut self) -> QuRegObject {
         assert!(self.qureg.borrow().width() < 64);
+        // Going over the limit still allocates the qubit, as the caller can't
+        // do without it; the interpreter stops once the builtin returns.
+        let _ = limits::reserve(1);
         self.qureg.borrow_mut().add_scratch(1);
         QuRegObject {
-            start: self.scratch() - 1,
-            end: self.scratch(),
-            scratch: true,
+            bits: vec![Qubit::Scratch(self.scratch() - 1)],
             qureg: self.qureg.clone(),
         }
     }
 
-    fn remove_scratch(self) {
-        if self.scratch {
+    // Adds n scratch qubits as a single register, the first added being its
+    // lowest qubit.
+    pub fn add_scratch_register(&mut self, n: usize) -> QuRegObject {
+        let mut bits = Vec::with_capacity(n);
+        for _ in 0..n {
+            bits.push(self.add_scratch().bits[0]);
+        }
+        QuRegObject {
+            bits: bits,
+            qureg: self.qureg.clone(),
+        }
+    }
+
+    pub fn remove_scratch_register(self) {
+        for q in self.to_vec().into_iter().rev() {
+            q.remove_scratch();
+        }
+    }
+
+    pub fn remove_scratch(self) {
+        if let Qubit::Scratch(i) = self.bits[0] {
             // Scratch reference must be a bit
             assert!(self.qubit());
-            if self.end != self.scratch() {
-                panic!("Scratch qubit {} deleted out of order!", self.start);
+            if i + 1 != self.scratch() {
+                panic!("Scratch qubit {} deleted out of order!", i);
             }
-            let bit = self.raw_start();
-            if self.qureg.borrow_mut().measure_bit(bit) {
-                panic!("Scratch qubit {} not properly cleared!", self.start);
+            let bit = self.raw_bit();
+            let qureg = &self.qureg;
+            // A suppressed run still has to give its scratch qubits back, but
+            // as nothing was applied to them they are sure to be clear.
+            if observer::suppressed() {
+                qureg.borrow_mut().measure_bit(bit);
+            } else if observer::apply("measure_bit", &[bit], None, || qureg.borrow_mut().measure_bit(bit)) {
+                limits::fail(format!("Scratch qubit {} not properly cleared!", i));
             }
+            limits::release(1);
         }
     }
 
     pub fn len(&self) -> usize {
-        self.end - self.start
+        self.bits.len()
+    }
+
+    pub fn qubits(&self) -> Vec<usize> {
+        self.bits.iter().map(|&b| self.raw(b)).collect()
     }
 
     pub fn get(&self, idx: usize) -> QuRegObject {
-        if idx > self.end - self.start {
-            panic!("Invalid index '{}' into QuReg.", idx);
+        if idx >= self.len() {
+            limits::fail(format!("Invalid index '{}' into QuReg.", idx));
         }
-        self.slice(idx, idx+1)
+        self.slice(idx, 1, 1)
     }
 
-    pub fn slice(&self, lb: usize, ub: usize) -> QuRegObject {
-        if lb >= ub || ub > self.end - self.start {
-            panic!("Invalid slice indicies '{}:{}' into QuReg.", lb, ub);
+    // Takes len qubits, every step-th one from start.
+    pub fn slice(&self, start: usize, len: usize, step: i64) -> QuRegObject {
+        if len == 0 {
+            limits::fail(format!("Found zero-length quantum register!"));
         }
+        let bits = (0..len).map(|i| self.bits[(start as i64 + i as i64 * step) as usize]).collect();
         QuRegObject {
-            start: self.start + lb,
-            end: self.start + ub,
-            scratch: self.scratch,
+            bits: bits,
             qureg: self.qureg.clone(),
         }
-    } 
+    }
+
+    // Joins two views of the same register into one, with self as its lower
+    // qubits.
+    pub fn cat(&self, other: &QuRegObject) -> QuRegObject {
+        if !Rc::ptr_eq(&self.qureg, &other.qureg) {
+            limits::fail(format!("Only views of the same quantum register may be concatenated."));
+        }
+        if self.overlaps(other) {
+            limits::fail(format!("Concatenated quantum registers may not share qubits."));
+        }
+        let mut bits = self.bits.clone();
+        bits.extend(other.bits.iter().cloned());
+        QuRegObject {
+            bits: bits,
+            qureg: self.qureg.clone(),
+        }
+    }
 
     qureg_fn_t!(hadamard);
     qureg_fn_t!(sigma_x);
@@ -121,7 +228,46 @@ impl QuRegObject {
     qureg_fn_t_g!(phase);
     qureg_fn_t_g!(phaseby);
 
-    fn to_vec(&self) -> Vec<QuRegObject> {
+    pub fn s_gate(&mut self) {
+        self.phaseby(PI / 2.0);
+    }
+
+    pub fn s_dagger(&mut self) {
+        self.phaseby(-PI / 2.0);
+    }
+
+    pub fn t_gate(&mut self) {
+        self.phaseby(PI / 4.0);
+    }
+
+    pub fn t_dagger(&mut self) {
+        self.phaseby(-PI / 4.0);
+    }
+
+    // sqrt(X) = H S H
+    pub fn sqrt_x(&mut self) {
+        self.hadamard();
+        self.s_gate();
+        self.hadamard();
+    }
+
+    pub fn sqrt_x_dagger(&mut self) {
+        self.hadamard();
+        self.s_dagger();
+        self.hadamard();
+    }
+
+    // U3(theta, phi, lambda) = e^(i(phi+lambda)/2) Rz(phi) Ry(theta) Rz(lambda),
+    // with the global phase kept so that the gate is exact when controlled.
+    // Its inverse is U3(-theta, -lambda, -phi).
+    pub fn u3(&mut self, theta: f64, phi: f64, lambda: f64) {
+        self.rotate_z(lambda);
+        self.rotate_y(theta);
+        self.rotate_z(phi);
+        self.phase((phi + lambda) / 2.0);
+    }
+
+    pub fn to_vec(&self) -> Vec<QuRegObject> {
         let mut v = Vec::with_capacity(self.len() + 2);
         for i in 0..self.len() {
             v.push(self.get(i));
@@ -129,8 +275,86 @@ impl QuRegObject {
         v
     }
 
+    fn check_same_register(&self, control: &QuRegObject) {
+        if !Rc::ptr_eq(&self.qureg, &control.qureg) {
+            limits::fail("Controlled gates must act on the same quantum register as their controls".to_string());
+        }
+    }
+
     fn overlaps(&self, other: &QuRegObject) -> bool {
-        self.raw_start() < other.raw_end() && other.raw_start() < self.raw_end()
+        self.bits.iter().any(|b| other.bits.contains(b))
+    }
+
+    // Splits the active controls along with `extra` into single qubits,
+    // checking that none of them are part of this register.
+    fn control_qubits(&self, controls: &[QuRegObject], extra: &[QuRegObject]) -> Vec<QuRegObject> {
+        let mut qubits = Vec::new();
+        for c in controls.iter().chain(extra.iter()) {
+            self.check_same_register(c);
+            if self.overlaps(c) {
+                limits::fail(format!("Controlled gate applied to one of its own control qubits!"));
+            }
+            qubits.extend(c.to_vec());
+        }
+        qubits
+    }
+
+    // If there are active controls, calls `f` with the raw index of each of
+    // this register's qubits, along with the raw index of a qubit which is
+    // set exactly when all the controls are. Returns false, doing nothing, if
+    // there are no active controls.
+    fn controlled<F: FnMut(&mut QuReg, usize, usize)>(&self, mut f: F) -> bool {
+        let controls = take_controls();
+        if controls.is_empty() {
+            restore_controls(controls);
+            return false;
+        }
+        let mut qubits = self.control_qubits(&controls[..], &[]);
+        let work = if qubits.len() > 1 {
+            let mut work = self.clone().add_scratch();
+            work.mcx(&mut qubits[..]);
+            Some(work)
+        } else {
+            None
+        };
+        {
+            let c = match work {
+                Some(ref w) => w.raw_bit(),
+                None => qubits[0].raw_bit(),
+            };
+            let targets = self.qubits();
+            let mut qm = self.qureg.borrow_mut();
+            for &i in targets.iter() {
+                f(&mut qm, c, i);
+            }
+        }
+        if let Some(mut work) = work {
+            work.mcx(&mut qubits[..]);
+            work.remove_scratch();
+        }
+        restore_controls(controls);
+        true
+    }
+
+    // Runs `f` with the active controls folded into a single scratch qubit,
+    // so that a long run of gates needn't recompute their conjunction for
+    // every gate.
+    pub fn fold_controls<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let controls = take_controls();
+        let mut qubits = self.control_qubits(&controls[..], &[]);
+        if qubits.len() < 2 {
+            restore_controls(controls);
+            return f();
+        }
+        let mut work = self.clone().add_scratch();
+        work.mcx(&mut qubits[..]);
+        restore_controls(vec![work.clone()]);
+        let result = f();
+        let _ = take_controls();
+        work.mcx(&mut qubits[..]);
+        work.remove_scratch();
+        restore_controls(controls);
+        result
     }
 
     fn cnot_half(target: &mut QuRegObject,
@@ -181,34 +405,43 @@ impl QuRegObject {
     }
 
     pub fn cnot(&mut self, control: &mut QuRegObject) {
-        assert!(Rc::ptr_eq(&self.qureg, &control.qureg));
+        self.check_same_register(control);
         assert!(!self.overlaps(control));
-        if control.qubit() {
-            let startc = control.raw_start();
-            let start = self.raw_start();
-            let end = self.raw_end();
+        if control.len() < 1 {
+            limits::fail(format!("Found zero-length quantum register!"));
+        }
+        let controls = take_controls();
+        let mut qubits = self.control_qubits(&controls[..], &[control.clone()]);
+        self.mcx(&mut qubits[..]);
+        restore_controls(controls);
+    }
+
+    // Flips every qubit of this register if all the given single qubits are
+    // set.
+    fn mcx(&mut self, control: &mut [QuRegObject]) {
+        if control.len() == 1 {
+            let startc = control[0].raw_bit();
+            let targets = self.qubits();
             let mut qm = self.qureg.borrow_mut();
-            for i in start..end {
-                qm.cnot(startc, i);
+            for &i in targets.iter() {
+                observer::apply("cnot", &[startc, i], None, || qm.cnot(startc, i));
             }
             return;
         } else if control.len() == 2 {
-            let startc = control.raw_start();
-            let start = self.raw_start();
-            let end = self.raw_end();
+            let startc1 = control[0].raw_bit();
+            let startc2 = control[1].raw_bit();
+            let targets = self.qubits();
             let mut qm = self.qureg.borrow_mut();
-            for i in start..end {
-                qm.toffoli(startc, startc+1, i);
+            for &i in targets.iter() {
+                observer::apply("toffoli", &[startc1, startc2, i], None, || qm.toffoli(startc1, startc2, i));
             }
             return;
-        } else if control.len() < 1 {
-            panic!("Found zero-length quantum register!");
         }
         let mut work = self.add_scratch();
         let k = control.len();
         let m = (2 + k) / 2;
-        let mut half1 = control.slice(0, k-m).to_vec();
-        let mut half2 = control.slice(k-m, k).to_vec();
+        let mut half1 = control[0..k-m].to_vec();
+        let mut half2 = control[k-m..k].to_vec();
         for i in 0..self.len() {
             let mut bit = self.get(i);
             half1.push(bit);
@@ -228,37 +461,138 @@ impl QuRegObject {
     }
 
     pub fn toffoli(&mut self, control1: &mut QuRegObject, control2: &mut QuRegObject) {
-        assert!(Rc::ptr_eq(&self.qureg, &control1.qureg));
-        assert!(Rc::ptr_eq(&self.qureg, &control2.qureg));
+        self.check_same_register(control1);
+        self.check_same_register(control2);
         assert!(self.qubit());
         assert!(control1.qubit());
         assert!(!self.overlaps(control1));
         assert!(control2.qubit());
         assert!(!self.overlaps(control2));
-        let start = self.raw_start();
-        let start1 = control1.raw_start();
-        let start2 = control2.raw_start();
-        self.qureg.borrow_mut().toffoli(start1, start2, start);
+        let controls = take_controls();
+        if !controls.is_empty() {
+            let mut qubits = self.control_qubits(&controls[..], &[control1.clone(), control2.clone()]);
+            self.mcx(&mut qubits[..]);
+            restore_controls(controls);
+            return;
+        }
+        restore_controls(controls);
+        let start = self.raw_bit();
+        let start1 = control1.raw_bit();
+        let start2 = control2.raw_bit();
+        let qureg = &self.qureg;
+        observer::apply("toffoli", &[start1, start2, start], None, || qureg.borrow_mut().toffoli(start1, start2, start));
     }
 
     pub fn cphase(&mut self, control: &mut QuRegObject) {
-        assert!(Rc::ptr_eq(&self.qureg, &control.qureg));
+        self.check_same_register(control);
         assert!(self.qubit());
         assert!(control.qubit());
         assert!(!self.overlaps(control));
-        let start = self.raw_start();
-        let startc = control.raw_start();
-        self.qureg.borrow_mut().cond_phase(startc, start);
+        // The phase libquantum applies depends on the distance between the
+        // qubits, which an extra control can't preserve
+        if CONTROLS.with(|c| !c.borrow().is_empty()) {
+            limits::fail("cphase may not be controlled; use cphaseby instead.".to_string());
+        }
+        let start = self.raw_bit();
+        let startc = control.raw_bit();
+        let qureg = &self.qureg;
+        observer::apply("cond_phase", &[startc, start], None, || qureg.borrow_mut().cond_phase(startc, start));
+    }
+
+    // Applies `f` to this register, conditional on all the qubits of
+    // `control` being set.
+    pub fn controlled_by<F: FnOnce(&mut QuRegObject)>(&mut self, control: &QuRegObject, f: F) {
+        self.check_same_register(control);
+        if control.len() < 1 {
+            limits::fail(format!("Found zero-length quantum register!"));
+        }
+        push_control(control.clone());
+        f(self);
+        pop_control();
     }
 
     pub fn cphaseby(&mut self, control: &mut QuRegObject, gamma: f64) {
-        assert!(Rc::ptr_eq(&self.qureg, &control.qureg));
-        assert!(self.qubit());
-        assert!(control.qubit());
-        assert!(!self.overlaps(control));
-        let start = self.raw_start();
-        let startc = control.raw_start();
-        self.qureg.borrow_mut().cond_phaseby(startc, start, gamma as f32);
+        self.controlled_by(control, |q| q.phaseby(gamma));
+    }
+
+    pub fn ch(&mut self, control: &mut QuRegObject) {
+        self.controlled_by(control, |q| q.hadamard());
+    }
+
+    pub fn crx(&mut self, control: &mut QuRegObject, gamma: f64) {
+        self.controlled_by(control, |q| q.rotate_x(gamma));
+    }
+
+    pub fn cry(&mut self, control: &mut QuRegObject, gamma: f64) {
+        self.controlled_by(control, |q| q.rotate_y(gamma));
+    }
+
+    pub fn crz(&mut self, control: &mut QuRegObject, gamma: f64) {
+        self.controlled_by(control, |q| q.rotate_z(gamma));
+    }
+
+    pub fn cu3(&mut self, control: &mut QuRegObject, theta: f64, phi: f64, lambda: f64) {
+        self.controlled_by(control, |q| q.u3(theta, phi, lambda));
+    }
+
+    // Applies an arbitrary unitary matrix to this register, one two-level
+    // unitary at a time.
+    pub fn unitary(&mut self, m: &Matrix) {
+        assert_eq!(m.size, 1 << self.len());
+        let mut bits = self.to_vec();
+        for step in m.decompose() {
+            QuRegObject::two_level(&mut bits[..], &step);
+        }
+    }
+
+    // Moves the amplitude of step.lo along a Gray code until it differs from
+    // step.hi in just one bit, applies the 2x2 matrix to that bit, and moves
+    // it back.
+    fn two_level(bits: &mut [QuRegObject], step: &TwoLevel) {
+        let diff = step.lo ^ step.hi;
+        let flips: Vec<usize> = (0..bits.len()).filter(|b| diff & (1 << b) != 0).collect();
+        let (last, path) = flips.split_last().unwrap();
+        let mut state = step.lo;
+        for &b in path.iter() {
+            QuRegObject::on_pattern(bits, b, state, |q| q.sigma_x());
+            state ^= 1 << b;
+        }
+        let m = if state & (1 << last) == 0 {
+            step.m
+        } else {
+            [step.m[3], step.m[2], step.m[1], step.m[0]]
+        };
+        let (alpha, theta, phi, lambda) = unitary::to_u3(&m);
+        QuRegObject::on_pattern(bits, *last, state, |q| {
+            q.u3(theta, phi, lambda);
+            q.phase(alpha);
+        });
+        for &b in path.iter().rev() {
+            state ^= 1 << b;
+            QuRegObject::on_pattern(bits, b, state, |q| q.sigma_x());
+        }
+    }
+
+    // Applies `f` to bits[target], conditional on every other bit matching
+    // the corresponding bit of `pattern`.
+    fn on_pattern<F: FnOnce(&mut QuRegObject)>(bits: &mut [QuRegObject], target: usize, pattern: usize, f: F) {
+        let others: Vec<usize> = (0..bits.len()).filter(|&b| b != target).collect();
+        let flip = |bits: &mut [QuRegObject]| uncontrolled(|| {
+            for &b in others.iter() {
+                if pattern & (1 << b) == 0 {
+                    bits[b].sigma_x();
+                }
+            }
+        });
+        flip(bits);
+        for &b in others.iter() {
+            push_control(bits[b].clone());
+        }
+        f(&mut bits[target]);
+        for _ in others.iter() {
+            pop_control();
+        }
+        flip(bits);
     }
 
     pub fn cflip(&mut self, control: &mut QuRegObject) {
@@ -276,76 +610,113 @@ impl QuRegObject {
     }
 
     pub fn all(&mut self) -> QuRegObject {
-        let mut scratch = self.add_scratch();
-        scratch.cnot(self);
-        scratch
+        uncontrolled(|| {
+            let mut scratch = self.add_scratch();
+            scratch.cnot(self);
+            scratch
+        })
     }
 
     pub fn iall(mut self, orig: &mut QuRegObject) {
-        self.cnot(orig);
-        self.remove_scratch();
+        uncontrolled(|| {
+            self.cnot(orig);
+            self.remove_scratch();
+        })
     }
 
     pub fn any(&mut self) -> QuRegObject {
-        let mut scratch = self.add_scratch();
-        self.sigma_x();
-        scratch.cnot(self);
-        self.sigma_x();
-        scratch.sigma_x();
-        scratch
+        uncontrolled(|| {
+            let mut scratch = self.add_scratch();
+            self.sigma_x();
+            scratch.cnot(self);
+            self.sigma_x();
+            scratch.sigma_x();
+            scratch
+        })
     }
 
     pub fn iany(mut self, orig: &mut QuRegObject) {
-        self.sigma_x();
-        orig.sigma_x();
-        self.cnot(orig);
-        orig.sigma_x();
-        self.remove_scratch();
+        uncontrolled(|| {
+            self.sigma_x();
+            orig.sigma_x();
+            self.cnot(orig);
+            orig.sigma_x();
+            self.remove_scratch();
+        })
     }
 
     pub fn not(&mut self) -> QuRegObject {
-        let mut scratch = self.add_scratch();
-        scratch.cnot(self);
-        scratch.sigma_x();
-        scratch
+        uncontrolled(|| {
+            let mut scratch = self.add_scratch();
+            scratch.cnot(self);
+            scratch.sigma_x();
+            scratch
+        })
     }
 
     pub fn inot(mut self, orig: &mut QuRegObject) {
-        self.sigma_x();
-        self.cnot(orig);
-        self.remove_scratch();
+        uncontrolled(|| {
+            self.sigma_x();
+            self.cnot(orig);
+            self.remove_scratch();
+        })
     }
 
     pub fn and(&mut self, other: &mut QuRegObject) -> QuRegObject {
-        let mut scratch = self.add_scratch();
-        scratch.toffoli(self, other);
-        scratch
+        uncontrolled(|| {
+            let mut scratch = self.add_scratch();
+            scratch.toffoli(self, other);
+            scratch
+        })
     }
 
     pub fn iand(mut self, control1: &mut QuRegObject, control2: &mut QuRegObject) {
-        self.toffoli(control1, control2);
-        self.remove_scratch();
+        uncontrolled(|| {
+            self.toffoli(control1, control2);
+            self.remove_scratch();
+        })
     } 
 
     pub fn or(&mut self, other: &mut QuRegObject) -> QuRegObject {
-        let mut scratch = self.add_scratch();
-        scratch.cnot(self);
-        scratch.cnot(other);
-        scratch.toffoli(self, other);
-        scratch
+        uncontrolled(|| {
+            let mut scratch = self.add_scratch();
+            scratch.cnot(self);
+            scratch.cnot(other);
+            scratch.toffoli(self, other);
+            scratch
+        })
     }
 
     pub fn ior(mut self, control1: &mut QuRegObject, control2: &mut QuRegObject) {
-        self.toffoli(control1, control2);
-        self.cnot(control2);
-        self.cnot(control1);
-        self.remove_scratch();
+        uncontrolled(|| {
+            self.toffoli(control1, control2);
+            self.cnot(control2);
+            self.cnot(control1);
+            self.remove_scratch();
+        })
     }
 
     pub fn measure(&mut self) -> i64 {
-        let start = self.raw_start();
-        let end = self.raw_end();
-        self.qureg.borrow_mut().measure_partial(start..end) as i64
+        if CONTROLS.with(|c| !c.borrow().is_empty()) {
+            limits::fail("Measurement may not be controlled.".to_string());
+        }
+        let qubits = self.qubits();
+        let qureg = &self.qureg;
+        observer::apply("measure", &qubits[..], None, || {
+            // Measures each run of adjacent qubits at once
+            let mut qm = qureg.borrow_mut();
+            let mut v = 0;
+            let mut i = 0;
+            while i < qubits.len() {
+                let mut j = i + 1;
+                while j < qubits.len() && qubits[j] == qubits[j-1] + 1 {
+                    j += 1;
+                }
+                v |= qm.measure_partial(qubits[i]..qubits[j-1]+1) << i;
+                i = j;
+            }
+            v
+        }) as i64
     }
 
     pub fn to_string(&self) -> String {
@@ -353,3 +724,83 @@ impl QuRegObject {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use super::QuRegObject;
+
+    // Each test sandwiches the gates between Hadamards, which turns a phase
+    // of -1 on |1> into a bit flip, so the result can be measured exactly.
+    fn sandwich<F: FnOnce(&mut QuRegObject)>(f: F) -> i64 {
+        let mut q = QuRegObject::new(1, 0).unwrap();
+        q.hadamard();
+        f(&mut q);
+        q.hadamard();
+        q.measure()
+    }
+
+    #[test]
+    fn s_squared_is_z() {
+        assert_eq!(sandwich(|q| { q.s_gate(); q.s_gate(); }), 1);
+        assert_eq!(sandwich(|q| { q.s_dagger(); q.s_dagger(); }), 1);
+    }
+
+    #[test]
+    fn s_dagger_undoes_s() {
+        assert_eq!(sandwich(|q| { q.s_gate(); q.s_dagger(); }), 0);
+    }
+
+    #[test]
+    fn t_squared_is_s() {
+        assert_eq!(sandwich(|q| { q.t_gate(); q.t_gate(); q.s_dagger(); }), 0);
+        assert_eq!(sandwich(|q| { q.t_dagger(); q.t_dagger(); q.s_gate(); }), 0);
+        assert_eq!(sandwich(|q| { for _ in 0..4 { q.t_gate(); } }), 1);
+    }
+
+    #[test]
+    fn sqrt_x_squared_is_x() {
+        let mut q = QuRegObject::new(1, 0).unwrap();
+        q.sqrt_x();
+        q.sqrt_x();
+        assert_eq!(q.measure(), 1);
+
+        let mut q = QuRegObject::new(1, 1).unwrap();
+        q.sqrt_x_dagger();
+        q.sqrt_x_dagger();
+        assert_eq!(q.measure(), 0);
+    }
+
+    #[test]
+    fn sqrt_x_dagger_undoes_sqrt_x() {
+        let mut q = QuRegObject::new(1, 0).unwrap();
+        q.sqrt_x();
+        q.sqrt_x_dagger();
+        assert_eq!(q.measure(), 0);
+    }
+
+    #[test]
+    fn u3_matches_known_gates() {
+        // U3(pi, 0, pi) = X
+        let mut q = QuRegObject::new(1, 0).unwrap();
+        q.u3(PI, 0.0, PI);
+        assert_eq!(q.measure(), 1);
+
+        // U3(pi/2, 0, pi) = H
+        let mut q = QuRegObject::new(1, 0).unwrap();
+        q.hadamard();
+        q.u3(PI / 2.0, 0.0, PI);
+        assert_eq!(q.measure(), 0);
+
+        // U3(0, 0, pi/2) = S
+        assert_eq!(sandwich(|q| { q.u3(0.0, 0.0, PI / 2.0); q.s_dagger(); }), 0);
+    }
+
+    #[test]
+    fn u3_inverse() {
+        let mut q = QuRegObject::new(1, 0).unwrap();
+        q.u3(0.3, 0.5, 0.7);
+        q.u3(-0.3, -0.7, -0.5);
+        assert_eq!(q.measure(), 0);
+    }
+}
@@ -15,15 +15,36 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use backend::runtime::limits;
+use backend::runtime::unitary::Matrix;
 use backend::runtime::value::Value;
 
 use std::f64;
 use std::vec::Vec;
 
+// Lets a builtin such as map call back into Quick code with the function it
+// was passed. Gives None if the call stopped the program with an error.
+pub trait Caller {
+    fn call(&mut self, f: Value, args: Vec<Value>) -> Option<Value>;
+}
+
+// For running builtins outside of the interpreter, such as when folding
+// constants, where nothing may be called back.
+pub struct NoCaller;
+
+impl Caller for NoCaller {
+    fn call(&mut self, _f: Value, _args: Vec<Value>) -> Option<Value> {
+        panic!("Internal Error, builtin called back into Quick code outside of the interpreter.");
+    }
+}
+
 pub struct IRTEntry {
-    pub irr: &'static Fn(&mut Vec<Value>),
+    pub irr: &'static Fn(&mut Vec<Value>, &mut Caller),
     pub rev: &'static Fn(&mut Vec<Value>, &mut Vec<Value>),
-    pub inv: &'static Fn(&mut Vec<Value>, &mut Vec<Value>)
+    pub inv: &'static Fn(&mut Vec<Value>, &mut Vec<Value>),
+    // Whether rev and inv are implemented, so that the builtin may be used
+    // in reversible code.
+    pub reversible: bool,
 }
 
 pub struct IRTFunction {
@@ -44,10 +65,10 @@ irt_table! {
         (inverse) = { simple_irt_inv_fn!(stack, aux, get, i); }
     }
 
-    fn[stack, aux] slice(3) {
-        (regular) = { simple_irt_fn!(stack, slice, i1, i2);  }
-        (reverse) = { simple_irt_rev_fn!(stack, aux, slice, i1, i2); }
-        (inverse) = { simple_irt_inv_fn!(stack, aux, slice, i1, i2); }
+    fn[stack, aux] slice(4) {
+        (regular) = { simple_irt_fn!(stack, slice, i1, i2, step);  }
+        (reverse) = { simple_irt_rev_fn!(stack, aux, slice, i1, i2, step); }
+        (inverse) = { simple_irt_inv_fn!(stack, aux, slice, i1, i2, step); }
     }
 
     fn[stack, aux] put(3) {
@@ -82,6 +103,62 @@ irt_table! {
         (inverse) = { simple_irt_inv_fn!(stack, aux, cat, s2); }
     }
 
+    fn[stack, _aux] push(2) { simple_irt_fn!(stack, push, e); }
+    fn[stack, _aux] pop(1) { simple_irt_fn!(stack, pop); }
+    fn[stack, _aux] insert(3) { simple_irt_fn!(stack, insert, i, e); }
+    fn[stack, _aux] remove(2) { simple_irt_fn!(stack, remove, i); }
+    fn[stack, _aux] reverse(1) { simple_irt_fn!(stack, reverse); }
+    fn[stack, _aux] copy(1) { simple_irt_fn!(stack, copy); }
+    fn[stack, _aux] keys(1) { simple_irt_fn!(stack, keys); }
+    fn[stack, _aux] values(1) { simple_irt_fn!(stack, values); }
+    fn[stack, _aux] has(2) { simple_irt_fn!(stack, has, k); }
+    fn[stack, _aux] iter(1) { simple_irt_fn!(stack, iter); }
+
+    fn[stack, caller] map(2) {
+        (calls) = {
+            let a = stack.pop().unwrap().as_array();
+            let f = stack.pop().unwrap();
+            let mut v = Vec::with_capacity(a.len());
+            for i in 0..a.len() {
+                v.push(call_back!(caller, f, a.get(i)));
+            }
+            stack.push(Value::new_array(v));
+        }
+    }
+
+    fn[stack, caller] filter(2) {
+        (calls) = {
+            let a = stack.pop().unwrap().as_array();
+            let f = stack.pop().unwrap();
+            let mut v = Vec::new();
+            for i in 0..a.len() {
+                if call_back!(caller, f, a.get(i)).as_bool() {
+                    v.push(a.get(i));
+                }
+            }
+            stack.push(Value::new_array(v));
+        }
+    }
+
+    fn[stack, caller] fold(3) {
+        (calls) = {
+            let a = stack.pop().unwrap().as_array();
+            let mut acc = stack.pop().unwrap();
+            let f = stack.pop().unwrap();
+            for i in 0..a.len() {
+                acc = call_back!(caller, f, acc, a.get(i));
+            }
+            stack.push(acc);
+        }
+    }
+
+    fn[stack, _aux] zip(2) { simple_irt_fn!(stack, zip, b); }
+    fn[stack, _aux] range(3) { simple_irt_fn!(stack, range, end, step); }
+    fn[stack, _aux] sort(1) { simple_irt_fn!(stack, sort); }
+    fn[stack, _aux] min(1) { simple_irt_fn!(stack, min); }
+    fn[stack, _aux] max(1) { simple_irt_fn!(stack, max); }
+    fn[stack, _aux] enumerate(1) { simple_irt_fn!(stack, enumerate); }
+
     fn[stack, aux] qalloc(2) {
         (regular) = { simple_irt_fn!(stack, qalloc, i);  }
         (reverse) = { simple_irt_rev_fn!(stack, aux, qalloc, i); }
@@ -171,6 +248,77 @@ irt_table! {
         (inverse) = { qureg_irt_inv_fn_t_g!(stack, aux, phaseby); }
     } 
 
+    fn[stack, aux] sgate(1) {
+        (regular) = { qureg_irt_fn_t!(stack, s_gate); }
+        (reverse) = { qureg_irt_rev_fn_t!(stack, aux, s_gate); }
+        (inverse) = { qureg_irt_inv_fn_t!(stack, aux, s_dagger); }
+    }
+
+    fn[stack, aux] sdag(1) {
+        (regular) = { qureg_irt_fn_t!(stack, s_dagger); }
+        (reverse) = { qureg_irt_rev_fn_t!(stack, aux, s_dagger); }
+        (inverse) = { qureg_irt_inv_fn_t!(stack, aux, s_gate); }
+    }
+
+    fn[stack, aux] tgate(1) {
+        (regular) = { qureg_irt_fn_t!(stack, t_gate); }
+        (reverse) = { qureg_irt_rev_fn_t!(stack, aux, t_gate); }
+        (inverse) = { qureg_irt_inv_fn_t!(stack, aux, t_dagger); }
+    }
+
+    fn[stack, aux] tdag(1) {
+        (regular) = { qureg_irt_fn_t!(stack, t_dagger); }
+        (reverse) = { qureg_irt_rev_fn_t!(stack, aux, t_dagger); }
+        (inverse) = { qureg_irt_inv_fn_t!(stack, aux, t_gate); }
+    }
+
+    fn[stack, aux] sqrtx(1) {
+        (regular) = { qureg_irt_fn_t!(stack, sqrt_x); }
+        (reverse) = { qureg_irt_rev_fn_t!(stack, aux, sqrt_x); }
+        (inverse) = { qureg_irt_inv_fn_t!(stack, aux, sqrt_x_dagger); }
+    }
+
+    fn[stack, aux] sqrtxdag(1) {
+        (regular) = { qureg_irt_fn_t!(stack, sqrt_x_dagger); }
+        (reverse) = { qureg_irt_rev_fn_t!(stack, aux, sqrt_x_dagger); }
+        (inverse) = { qureg_irt_inv_fn_t!(stack, aux, sqrt_x); }
+    }
+
+    fn[stack, aux] u3(4) {
+        (regular) = {
+            let lambda = stack.pop().unwrap().as_float();
+            let phi = stack.pop().unwrap().as_float();
+            let theta = stack.pop().unwrap().as_float();
+            let mut q = stack.pop().unwrap().as_qureg();
+            q.u3(theta, phi, lambda);
+            stack.push(Value::QuReg(q));
+        }
+        (reverse) = {
+            let lambda = stack.pop().unwrap();
+            let phi = stack.pop().unwrap();
+            let theta = stack.pop().unwrap();
+            let mut q = stack.pop().unwrap().as_qureg();
+            aux.push(Value::QuReg(q.clone()));
+            aux.push(theta.clone());
+            aux.push(phi.clone());
+            aux.push(lambda.clone());
+            q.u3(theta.as_float(), phi.as_float(), lambda.as_float());
+            stack.push(Value::QuReg(q));
+        }
+        (inverse) = {
+            let _ = stack.pop().unwrap();
+            let lambda = aux.pop().unwrap();
+            let phi = aux.pop().unwrap();
+            let theta = aux.pop().unwrap();
+            let q = aux.pop().unwrap();
+            q.clone().as_qureg().u3(-theta.clone().as_float(), -lambda.clone().as_float(), -phi.clone().as_float());
+            stack.push(q);
+            stack.push(theta);
+            stack.push(phi);
+            stack.push(lambda);
+        }
+    }
+
     fn[stack, aux] all(1) {
         (reverse) = {
             let mut t = stack.pop().unwrap().as_qureg();
@@ -201,33 +349,41 @@ irt_table! {
         }
     }
 
-    fn[stack, _aux] cnot(2) {
-        let mut t = stack.pop().unwrap().as_qureg();
-        let mut c = stack.pop().unwrap().as_qureg();
-        t.cnot(&mut c);
-        stack.push(Value::QuReg(t));
+    fn[stack, aux] cnot(2) {
+        (self_inverse) = {
+            let mut t = stack.pop().unwrap().as_qureg();
+            let mut c = stack.pop().unwrap().as_qureg();
+            t.cnot(&mut c);
+            stack.push(Value::QuReg(t));
+        }
     }
 
-    fn[stack, _aux] swap(2) {
-        let mut t1 = stack.pop().unwrap().as_qureg();
-        let mut t2 = stack.pop().unwrap().as_qureg();
-        t1.swap(&mut t2);
-        stack.push(Value::QuReg(t1));
+    fn[stack, aux] swap(2) {
+        (self_inverse) = {
+            let mut t1 = stack.pop().unwrap().as_qureg();
+            let mut t2 = stack.pop().unwrap().as_qureg();
+            t1.swap(&mut t2);
+            stack.push(Value::QuReg(t1));
+        }
     }
 
-    fn[stack, _aux] cflip(2) {
-        let mut t = stack.pop().unwrap().as_qureg();
-        let mut c = stack.pop().unwrap().as_qureg();
-        t.cflip(&mut c);
-        stack.push(Value::QuReg(t));
+    fn[stack, aux] cflip(2) {
+        (self_inverse) = {
+            let mut t = stack.pop().unwrap().as_qureg();
+            let mut c = stack.pop().unwrap().as_qureg();
+            t.cflip(&mut c);
+            stack.push(Value::QuReg(t));
+        }
     }
 
-    fn[stack, _aux] toffoli(3) {
-        let mut t = stack.pop().unwrap().as_qureg();
-        let mut c1 = stack.pop().unwrap().as_qureg();
-        let mut c2 = stack.pop().unwrap().as_qureg();
-        t.toffoli(&mut c1, &mut c2);
-        stack.push(Value::QuReg(t));
+    fn[stack, aux] toffoli(3) {
+        (self_inverse) = {
+            let mut t = stack.pop().unwrap().as_qureg();
+            let mut c1 = stack.pop().unwrap().as_qureg();
+            let mut c2 = stack.pop().unwrap().as_qureg();
+            t.toffoli(&mut c1, &mut c2);
+            stack.push(Value::QuReg(t));
+        }
     }
 
     fn[stack, _aux] cphase(2) {
@@ -237,22 +393,224 @@ irt_table! {
         stack.push(Value::QuReg(t));
     }
 
-    fn[stack, _aux] cphaseby(3) {
-        let g = stack.pop().unwrap().as_float();
-        let mut t = stack.pop().unwrap().as_qureg();
-        let mut c = stack.pop().unwrap().as_qureg();
-        t.cphaseby(&mut c, g);
-        stack.push(Value::QuReg(t));
+    fn[stack, aux] cphaseby(3) {
+        (regular) = { qureg_irt_fn_c_t_g!(stack, cphaseby); }
+        (reverse) = { qureg_irt_rev_fn_c_t_g!(stack, aux, cphaseby); }
+        (inverse) = { qureg_irt_inv_fn_c_t_g!(stack, aux, cphaseby); }
+    }
+
+    fn[stack, aux] crx(3) {
+        (regular) = { qureg_irt_fn_c_t_g!(stack, crx); }
+        (reverse) = { qureg_irt_rev_fn_c_t_g!(stack, aux, crx); }
+        (inverse) = { qureg_irt_inv_fn_c_t_g!(stack, aux, crx); }
+    }
+
+    fn[stack, aux] cry(3) {
+        (regular) = { qureg_irt_fn_c_t_g!(stack, cry); }
+        (reverse) = { qureg_irt_rev_fn_c_t_g!(stack, aux, cry); }
+        (inverse) = { qureg_irt_inv_fn_c_t_g!(stack, aux, cry); }
+    }
+
+    fn[stack, aux] crz(3) {
+        (regular) = { qureg_irt_fn_c_t_g!(stack, crz); }
+        (reverse) = { qureg_irt_rev_fn_c_t_g!(stack, aux, crz); }
+        (inverse) = { qureg_irt_inv_fn_c_t_g!(stack, aux, crz); }
+    }
+
+    fn[stack, aux] ch(2) {
+        (self_inverse) = {
+            let mut t = stack.pop().unwrap().as_qureg();
+            let mut c = stack.pop().unwrap().as_qureg();
+            t.ch(&mut c);
+            stack.push(Value::QuReg(t));
+        }
+    }
+
+    fn[stack, aux] cu3(5) {
+        (regular) = {
+            let lambda = stack.pop().unwrap().as_float();
+            let phi = stack.pop().unwrap().as_float();
+            let theta = stack.pop().unwrap().as_float();
+            let mut t = stack.pop().unwrap().as_qureg();
+            let mut c = stack.pop().unwrap().as_qureg();
+            t.cu3(&mut c, theta, phi, lambda);
+            stack.push(Value::QuReg(t));
+        }
+        (reverse) = {
+            let lambda = stack.pop().unwrap();
+            let phi = stack.pop().unwrap();
+            let theta = stack.pop().unwrap();
+            let mut t = stack.pop().unwrap().as_qureg();
+            let c = stack.pop().unwrap();
+            aux.push(c.clone());
+            aux.push(Value::QuReg(t.clone()));
+            aux.push(theta.clone());
+            aux.push(phi.clone());
+            aux.push(lambda.clone());
+            t.cu3(&mut c.as_qureg(), theta.as_float(), phi.as_float(), lambda.as_float());
+            stack.push(Value::QuReg(t));
+        }
+        (inverse) = {
+            let _ = stack.pop().unwrap();
+            let lambda = aux.pop().unwrap();
+            let phi = aux.pop().unwrap();
+            let theta = aux.pop().unwrap();
+            let t = aux.pop().unwrap();
+            let c = aux.pop().unwrap();
+            t.clone().as_qureg().cu3(&mut c.clone().as_qureg(), -theta.clone().as_float(),
+                                     -lambda.clone().as_float(), -phi.clone().as_float());
+            stack.push(c);
+            stack.push(t);
+            stack.push(theta);
+            stack.push(phi);
+            stack.push(lambda);
+        }
+    }
+
+    fn[stack, aux] gate(2) {
+        (regular) = {
+            let m = stack.pop().unwrap();
+            let mut q = stack.pop().unwrap().as_qureg();
+            q.unitary(&Matrix::from_value(m, q.len()));
+            stack.push(Value::QuReg(q));
+        }
+        (reverse) = {
+            let m = stack.pop().unwrap();
+            let mut q = stack.pop().unwrap().as_qureg();
+            aux.push(Value::QuReg(q.clone()));
+            aux.push(m.clone());
+            q.unitary(&Matrix::from_value(m, q.len()));
+            stack.push(Value::QuReg(q));
+        }
+        (inverse) = {
+            let _ = stack.pop().unwrap();
+            let m = aux.pop().unwrap();
+            let q = aux.pop().unwrap();
+            let mut r = q.clone().as_qureg();
+            let len = r.len();
+            r.unitary(&Matrix::from_value(m.clone(), len).adjoint());
+            stack.push(q);
+            stack.push(m);
+        }
+    }
+
+    fn[stack, aux] qadd(2) {
+        (apply) = {
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            b.add_ripple(&mut a);
+            stack.push(Value::QuReg(b));
+        }
+        (unapply) = {
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            b.sub_ripple(&mut a);
+            stack.push(Value::QuReg(b));
+        }
+    }
+
+    fn[stack, aux] qftadd(2) {
+        (apply) = {
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            b.add_fourier(&mut a, 1.0);
+            stack.push(Value::QuReg(b));
+        }
+        (unapply) = {
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            b.add_fourier(&mut a, -1.0);
+            stack.push(Value::QuReg(b));
+        }
+    }
+
+    fn[stack, aux] qaddc(2) {
+        (apply) = {
+            let k = stack.pop().unwrap().as_int();
+            let mut b = stack.pop().unwrap().as_qureg();
+            b.add_constant(k);
+            stack.push(Value::QuReg(b));
+        }
+        (unapply) = {
+            let k = stack.pop().unwrap().as_int();
+            let mut b = stack.pop().unwrap().as_qureg();
+            b.add_constant(-k);
+            stack.push(Value::QuReg(b));
+        }
+    }
+
+    fn[stack, aux] qmul(3) {
+        (apply) = {
+            let mut p = stack.pop().unwrap().as_qureg();
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            p.add_product(&mut a, &mut b, 1.0);
+            stack.push(Value::QuReg(p));
+        }
+        (unapply) = {
+            let mut p = stack.pop().unwrap().as_qureg();
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            p.add_product(&mut a, &mut b, -1.0);
+            stack.push(Value::QuReg(p));
+        }
+    }
+
+    fn[stack, aux] qmodexp(4) {
+        (apply) = {
+            let n = stack.pop().unwrap().as_int();
+            let g = stack.pop().unwrap().as_int();
+            let mut y = stack.pop().unwrap().as_qureg();
+            let mut x = stack.pop().unwrap().as_qureg();
+            y.mul_power_mod(&mut x, g, n);
+            stack.push(Value::QuReg(y));
+        }
+        (unapply) = {
+            let n = stack.pop().unwrap().as_int();
+            let g = stack.pop().unwrap().as_int();
+            let mut y = stack.pop().unwrap().as_qureg();
+            let mut x = stack.pop().unwrap().as_qureg();
+            y.div_power_mod(&mut x, g, n);
+            stack.push(Value::QuReg(y));
+        }
+    }
+
+    fn[stack, aux] qlt(2) {
+        (reverse) = {
+            let mut b = stack.pop().unwrap().as_qureg();
+            let mut a = stack.pop().unwrap().as_qureg();
+            let s = a.less_than(&mut b);
+            aux.push(Value::QuReg(a));
+            aux.push(Value::QuReg(b));
+            stack.push(Value::QuReg(s));
+        }
+        (inverse) = {
+            let s = stack.pop().unwrap().as_qureg();
+            let mut b = aux.pop().unwrap().as_qureg();
+            let mut a = aux.pop().unwrap().as_qureg();
+            s.iless_than(&mut a, &mut b);
+            stack.push(Value::QuReg(a));
+            stack.push(Value::QuReg(b));
+        }
     }
 
     fn[stack, _aux] measure(1) {
         let s = stack.pop().unwrap();
         let value = match s {
             Value::QuReg(mut q) => Value::Int(q.measure()),
-            _ => panic!("Measurement only available for QuReg."),
+            _ => limits::fail("Measurement only available for QuReg.".to_string()),
         };
         stack.push(value);
     }
+
+    fn[stack, _aux] inverse(1) {
+        let f = stack.pop().unwrap();
+        stack.push(match f {
+            Value::Func(ft) => Value::Adjoint(ft),
+            Value::Adjoint(ft) => Value::Func(ft),
+            _ => limits::fail("Only functions have an inverse.".to_string()),
+        });
+    }
 }
 
 pub fn printf(fmt: &String, args: &[Value]) {
@@ -0,0 +1,91 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::vec::Vec;
+
+use backend::runtime::limits;
+use backend::runtime::value::Value;
+use util::string_table::{self, StringToken};
+
+// The name of a struct and the names of its fields, in the order they were
+// declared, shared by every value of the struct.
+#[derive(Debug)]
+pub struct StructLayout {
+    pub name: StringToken,
+    pub fields: Vec<StringToken>,
+}
+
+impl StructLayout {
+    fn position(&self, field: &Value) -> usize {
+        let position = match *field {
+            Value::String(s) => self.fields.iter().position(|&f| f == s),
+            _ => None,
+        };
+        match position {
+            Some(i) => i,
+            None => limits::fail(format!("Struct {} has no field '{}'.", string_table::get(self.name), field.clone().as_string())),
+        }
+    }
+}
+
+// A value of a struct, whose fields are named by its layout. Like an array,
+// copies of a struct share its fields, so that changes made through one are
+// seen by all.
+#[derive(Debug, Clone)]
+pub struct StructObject {
+    layout: Rc<StructLayout>,
+    fields: Rc<RefCell<Vec<Value>>>,
+}
+
+impl StructObject {
+    pub fn from_vec(layout: Rc<StructLayout>, fields: Vec<Value>) -> StructObject {
+        StructObject {
+            layout: layout,
+            fields: Rc::new(RefCell::new(fields.into_iter().map(|v| v.fork()).collect())),
+        }
+    }
+
+    pub fn get(&self, field: Value) -> Value {
+        let i = self.layout.position(&field);
+        self.fields.borrow()[i].clone()
+    }
+
+    pub fn put(&mut self, field: Value, value: Value) {
+        let i = self.layout.position(&field);
+        self.fields.borrow_mut()[i] = value.fork();
+    }
+
+    pub fn copy(&self) -> StructObject {
+        StructObject {
+            layout: self.layout.clone(),
+            fields: Rc::new(RefCell::new(self.fields.borrow().iter().map(|v| v.clone().fork()).collect())),
+        }
+    }
+
+    pub fn to_string(&self) -> String {
+        let fields: Vec<String> = self.layout.fields.iter().zip(self.fields.borrow().iter()).map(|(f, v)| {
+            format!("{}: {}", string_table::get(*f), v.clone().as_string())
+        }).collect();
+        if fields.is_empty() {
+            format!("{} {{ }}", string_table::get(self.layout.name))
+        } else {
+            format!("{} {{ {} }}", string_table::get(self.layout.name), fields.join(", "))
+        }
+    }
+}
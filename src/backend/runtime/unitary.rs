@@ -0,0 +1,218 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Arbitrary unitary matrices, broken down into gates libquantum can apply.
+// Row and column x of a matrix on a register q stand for the basis state in
+// which q[i] is bit i of x.
+
+use std::vec::Vec;
+
+use backend::runtime::limits;
+use backend::runtime::value::Value;
+
+const EPSILON: f64 = 1e-9;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    fn new(re: f64, im: f64) -> Complex {
+        Complex { re: re, im: im }
+    }
+
+    fn conj(self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    fn norm(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    fn arg(self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    fn add(self, o: Complex) -> Complex {
+        Complex::new(self.re + o.re, self.im + o.im)
+    }
+
+    fn mul(self, o: Complex) -> Complex {
+        Complex::new(self.re * o.re - self.im * o.im, self.re * o.im + self.im * o.re)
+    }
+
+    fn scale(self, s: f64) -> Complex {
+        Complex::new(self.re * s, self.im * s)
+    }
+
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+pub struct Matrix {
+    pub size: usize,
+    m: Vec<Complex>,
+}
+
+// A unitary acting on just two basis states, lo and hi, as the 2x2 matrix
+// [[m[0], m[1]], [m[2], m[3]]] with lo first.
+pub struct TwoLevel {
+    pub lo: usize,
+    pub hi: usize,
+    pub m: [Complex; 4],
+}
+
+fn to_complex(v: Value) -> Complex {
+    match v {
+        Value::Int(_) | Value::Float(_) => Complex::new(v.as_float(), 0.0),
+        Value::Array(a) => {
+            if a.len() != 2 {
+                limits::fail("Complex matrix entries must be pairs of real and imaginary parts.".to_string());
+            }
+            Complex::new(a.get(0).as_float(), a.get(1).as_float())
+        },
+        _ => limits::fail(format!("Invalid matrix entry of type {}.", v.kind())),
+    }
+}
+
+impl Matrix {
+    // Reads an array of rows, each entry being a number or a pair of real
+    // and imaginary parts, checking that it is a unitary 2^k by 2^k matrix.
+    pub fn from_value(v: Value, k: usize) -> Matrix {
+        let size = 1 << k;
+        let rows = match v {
+            Value::Array(a) => a,
+            _ => limits::fail("Gate matrix must be an array of rows.".to_string()),
+        };
+        if rows.len() != size {
+            limits::fail(format!("Gate on {} qubits needs a {}x{} matrix.", k, size, size));
+        }
+        let mut m = Vec::with_capacity(size * size);
+        for i in 0..size {
+            match rows.get(i) {
+                Value::Array(ref row) if row.len() == size => {
+                    for j in 0..size {
+                        m.push(to_complex(row.get(j)));
+                    }
+                },
+                _ => limits::fail(format!("Gate on {} qubits needs a {}x{} matrix.", k, size, size)),
+            }
+        }
+        let matrix = Matrix { size: size, m: m };
+        if !matrix.is_unitary() {
+            limits::fail("Gate matrix is not unitary.".to_string());
+        }
+        matrix
+    }
+
+    fn at(&self, i: usize, j: usize) -> Complex {
+        self.m[i * self.size + j]
+    }
+
+    fn is_unitary(&self) -> bool {
+        for i in 0..self.size {
+            for j in 0..self.size {
+                let mut dot = Complex::new(0.0, 0.0);
+                for l in 0..self.size {
+                    dot = dot.add(self.at(l, i).conj().mul(self.at(l, j)));
+                }
+                let expect = if i == j { 1.0 } else { 0.0 };
+                if (dot.re - expect).abs() > 1e-6 || dot.im.abs() > 1e-6 {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn adjoint(&self) -> Matrix {
+        let mut m = Vec::with_capacity(self.m.len());
+        for i in 0..self.size {
+            for j in 0..self.size {
+                m.push(self.at(j, i).conj());
+            }
+        }
+        Matrix { size: self.size, m: m }
+    }
+
+    // Breaks the matrix down into two-level unitaries, in the order they
+    // should be applied. Each column is cleared in turn by rotating its
+    // entries into the diagonal, which leaves the identity once the phase of
+    // the last entry is undone; the inverses of those steps, applied in
+    // reverse, then make up the matrix.
+    pub fn decompose(&self) -> Vec<TwoLevel> {
+        let n = self.size;
+        let mut w = self.m.clone();
+        let mut steps = Vec::new();
+        for j in 0..n-1 {
+            for i in j+1..n {
+                let a = w[j * n + j];
+                let b = w[i * n + j];
+                if b.norm() < EPSILON {
+                    continue;
+                }
+                let norm = a.norm().hypot(b.norm());
+                let g = [a.conj().scale(1.0 / norm), b.conj().scale(1.0 / norm),
+                         b.neg().scale(1.0 / norm), a.scale(1.0 / norm)];
+                for c in 0..n {
+                    let (x, y) = (w[j * n + c], w[i * n + c]);
+                    w[j * n + c] = g[0].mul(x).add(g[1].mul(y));
+                    w[i * n + c] = g[2].mul(x).add(g[3].mul(y));
+                }
+                steps.push(TwoLevel { lo: j, hi: i, m: g });
+            }
+            // Clear whatever phase is left on the diagonal
+            let d = w[j * n + j];
+            if (d.re - 1.0).abs() > EPSILON || d.im.abs() > EPSILON {
+                let g = [d.conj(), Complex::new(0.0, 0.0), Complex::new(0.0, 0.0), Complex::new(1.0, 0.0)];
+                for c in 0..n {
+                    w[j * n + c] = d.conj().mul(w[j * n + c]);
+                }
+                steps.push(TwoLevel { lo: j, hi: j + 1, m: g });
+            }
+        }
+        let d = w[n * n - 1];
+        if (d.re - 1.0).abs() > EPSILON || d.im.abs() > EPSILON {
+            let one = Complex::new(1.0, 0.0);
+            let zero = Complex::new(0.0, 0.0);
+            steps.push(TwoLevel { lo: n - 2, hi: n - 1, m: [one, zero, zero, d.conj()] });
+        }
+        steps.reverse();
+        for s in steps.iter_mut() {
+            let m = s.m;
+            s.m = [m[0].conj(), m[2].conj(), m[1].conj(), m[3].conj()];
+        }
+        steps
+    }
+}
+
+// Finds (alpha, theta, phi, lambda) such that the unitary 2x2 matrix m is
+// e^(i alpha) U3(theta, phi, lambda).
+pub fn to_u3(m: &[Complex; 4]) -> (f64, f64, f64, f64) {
+    let theta = 2.0 * m[2].norm().atan2(m[0].norm());
+    if m[0].norm() < EPSILON {
+        return (0.0, theta, m[2].arg(), m[1].neg().arg());
+    }
+    let alpha = m[0].arg();
+    if m[2].norm() < EPSILON {
+        return (alpha, theta, 0.0, m[3].arg() - alpha);
+    }
+    (alpha, theta, m[2].arg() - alpha, m[1].neg().arg() - alpha)
+}
@@ -15,10 +15,18 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use backend::runtime::arith::{self, Operand};
 use backend::runtime::array::ArrayObject;
+use backend::runtime::limits;
+use backend::runtime::map::MapObject;
 use backend::runtime::qureg::QuRegObject;
+use backend::runtime::structure::{StructLayout, StructObject};
 use backend::bytecode::FunctionToken;
+use util::ops::*;
+use util::string_table::{self, StringToken};
 
+use std::cmp::Ordering;
+use std::rc::Rc;
 use std::ops::{Add, Sub, Mul, Div, Rem};
 
 // TODO: This value representation is grossly suboptimal, but is good for
@@ -31,8 +39,13 @@ pub enum Value {
     Int(i64),
     Bool(bool),
     Float(f64),
+    String(StringToken),
     Func(FunctionToken),
+    Adjoint(FunctionToken),
     Array(ArrayObject),
+    Tuple(Rc<Vec<Value>>),
+    Map(MapObject),
+    Struct(StructObject),
     QuReg(QuRegObject),
 }
 
@@ -70,6 +83,28 @@ impl Value {
         Value::Array(ArrayObject::from_vec(v))
     }
 
+    pub fn new_tuple(v: Vec<Value>) -> Value {
+        Value::Tuple(Rc::new(v.into_iter().map(|e| e.fork()).collect()))
+    }
+
+    // The value to store in a variable or element, which for an array is a
+    // view of its own, so that changing its size or order through one
+    // variable leaves the others as they were.
+    pub fn fork(self) -> Value {
+        match self {
+            Value::Array(a) => Value::Array(a.fork()),
+            v => v,
+        }
+    }
+
+    pub fn new_map(entries: Vec<(Value, Value)>) -> Value {
+        Value::Map(MapObject::from_vec(entries))
+    }
+
+    pub fn new_struct(layout: Rc<StructLayout>, fields: Vec<Value>) -> Value {
+        Value::Struct(StructObject::from_vec(layout, fields))
+    }
+
     arith_method!(add);
     arith_method!(sub);
     arith_method!(mul);
@@ -135,6 +170,82 @@ impl Value {
         }
     }
 
+    // Operators other than and and or compute their result into scratch
+    // qubits when either operand is a quantum register, taking the other as
+    // an integer.
+    fn quantum_operands(&self, other: &Value) -> Option<(Operand, Operand)> {
+        let operand = |v: &Value| match *v {
+            Value::QuReg(ref q) => Operand::Quantum(q.clone()),
+            Value::Int(v) => Operand::Classical(v),
+            Value::Bool(v) => Operand::Classical(v as i64),
+            _ => limits::fail(format!("Invalid operand {} to an operator on a quantum register", v.kind())),
+        };
+        match (self, other) {
+            (&Value::QuReg(_), _) | (_, &Value::QuReg(_)) => Some((operand(self), operand(other))),
+            _ => None,
+        }
+    }
+
+    // Whether op2 would compute its result into scratch qubits, which only
+    // the inverse of a reversible operation clears.
+    pub fn computes_scratch(&self, op: BinOp, other: &Value) -> bool {
+        match op {
+            BinOp::And | BinOp::Or => false,
+            _ => match (self, other) {
+                (&Value::QuReg(_), _) | (_, &Value::QuReg(_)) => true,
+                _ => false,
+            },
+        }
+    }
+
+    pub fn op2(self, op: BinOp, other: Value) -> Value {
+        match op {
+            BinOp::And | BinOp::Or => (),
+            _ => if let Some((mut a, mut b)) = self.quantum_operands(&other) {
+                return Value::QuReg(arith::compute(op, &mut a, &mut b));
+            },
+        }
+        match op {
+            BinOp::Add => self.add(other),
+            BinOp::Sub => self.sub(other),
+            BinOp::Mul => self.mul(other),
+            BinOp::Div => self.div(other),
+            BinOp::Rem => self.rem(other),
+            BinOp::Pow => self.pow(other),
+            BinOp::Lt => self.lt(other),
+            BinOp::Gt => self.gt(other),
+            BinOp::Le => self.le(other),
+            BinOp::Ge => self.ge(other),
+            BinOp::Eq => self.eq(other),
+            BinOp::Ne => self.ne(other),
+            BinOp::And => self.and(other),
+            BinOp::Or => self.or(other),
+            BinOp::BAnd => self.band(other),
+            BinOp::BOr => self.bor(other),
+            BinOp::BXor => self.bxor(other),
+        }
+    }
+
+    // Undoes op2 on the operands it was given, where that left qubits to be
+    // cleared.
+    pub fn iop2(self, op: BinOp, other1: Value, other2: Value) {
+        match op {
+            BinOp::And => self.iand(other1, other2),
+            BinOp::Or => self.ior(other1, other2),
+            _ => if let Some((mut a, mut b)) = other1.quantum_operands(&other2) {
+                arith::uncompute(self.as_qureg(), op, &mut a, &mut b);
+            },
+        }
+    }
+
+    pub fn op1(self, op: UnOp) -> Value {
+        match op {
+            UnOp::Neg => self.neg(),
+            UnOp::Not => self.not(),
+            UnOp::BNot => self.bnot(),
+        }
+    }
+
     pub fn band(self, other: Value) -> Value {
         Value::Int(self.as_int() & other.as_int())
     } 
@@ -151,7 +262,7 @@ impl Value {
         match self {
             Value::Int(v) => Value::Int(-v),
             Value::Float(v) => Value::Float(-v),
-            _ => panic!("Negation only available for Int and Float"),
+            _ => limits::fail(format!("Negation only available for Int and Float")),
         }
     }
 
@@ -183,8 +294,10 @@ impl Value {
         match self {
             Value::Int(_) => Value::Int(64),
             Value::Array(a) => Value::Int(a.len() as i64),
+            Value::Tuple(t) => Value::Int(t.len() as i64),
+            Value::Map(m) => Value::Int(m.len() as i64),
             Value::QuReg(q) => Value::Int(q.len() as i64),
-            _ => panic!("Length operation not available for {:?}", &self),
+            _ => limits::fail(format!("Length operation not available for {}", self.kind())),
         }
     }
 
@@ -192,58 +305,310 @@ impl Value {
         match self {
             Value::Int(v) => {
                 assert!(v > 0);
-                Value::QuReg(QuRegObject::new(v as usize, init.as_int()))
+                match QuRegObject::new(v as usize, init.as_int()) {
+                    Some(q) => Value::QuReg(q),
+                    None => Value::Null,
+                }
             },
-            _ => panic!("Must use an integer to allocate a quantum register!"),
+            _ => limits::fail(format!("Must use an integer to allocate a quantum register!")),
         }
     }
 
     pub fn get(self, index: Value) -> Value {
-        let idx = index.as_int() as usize;
         match self {
-            Value::Int(v) => Value::Int((v & (1 << idx)) >> idx),
-            Value::Array(v) => v.get(idx),
-            Value::QuReg(q) => Value::QuReg(q.get(idx)),
-            _ => panic!("Get operation not available for {:?}", &self),
+            Value::Int(v) => {
+                let idx = resolve_index(index, 64, "Int");
+                Value::Int((v >> idx) & 1)
+            },
+            Value::Array(v) => {
+                let idx = resolve_index(index, v.len(), "Array");
+                v.get(idx)
+            },
+            Value::Tuple(t) => {
+                let idx = resolve_index(index, t.len(), "Tuple");
+                t[idx].clone()
+            },
+            Value::Map(m) => m.get(index),
+            Value::Struct(r) => r.get(index),
+            Value::QuReg(q) => {
+                let idx = resolve_index(index, q.len(), "QuReg");
+                Value::QuReg(q.get(idx))
+            },
+            _ => limits::fail(format!("Get operation not available for {}", self.kind())),
         }
     }
 
     pub fn put(self, index: Value, value: Value) -> Value {
-        let idx = index.as_int() as usize;
         match self {
-            Value::Array(mut v) => v.put(idx, value.clone()),
-            _ => panic!("Put operation not available for {:?}", &self),
+            Value::Array(mut v) => {
+                let idx = resolve_index(index, v.len(), "Array");
+                v.put(idx, value.clone())
+            },
+            Value::Map(mut m) => m.put(index, value.clone()),
+            Value::Struct(mut r) => r.put(index, value.clone()),
+            _ => limits::fail(format!("Put operation not available for {}", self.kind())),
         }
         value
     }
     
-    pub fn slice(self, index1: Value, index2: Value) -> Value {
-        let idx1 = index1.as_int() as usize;
-        let idx2 = index2.as_int() as usize;
+    // Either bound of a slice may be left out, as may its step, which is
+    // otherwise 1.
+    pub fn slice(self, index1: Value, index2: Value, step: Value) -> Value {
         match self {
-            Value::Array(v) => v.slice(idx1, idx2),
-            Value::QuReg(q) => Value::QuReg(q.slice(idx1, idx2)),
-            _ => panic!("Slice operation not available for {:?}", &self),
+            Value::Array(v) => {
+                let (start, len, step) = resolve_slice(index1, index2, step, v.len(), "Array");
+                v.slice(start, len, step)
+            },
+            Value::QuReg(q) => {
+                let (start, len, step) = resolve_slice(index1, index2, step, q.len(), "QuReg");
+                if len == 0 {
+                    limits::fail(format!("Empty slice of a QuReg."));
+                }
+                Value::QuReg(q.slice(start, len, step))
+            },
+            _ => limits::fail(format!("Slice operation not available for {}", self.kind())),
         }
     }
 
     pub fn cat(self, other: Value) -> Value {
-        if let Value::Array(mut v) = self {
+        if let Value::QuReg(ref q1) = self {
+            if let Value::QuReg(ref q2) = other {
+                return Value::QuReg(q1.cat(q2));
+            }
+        }
+        if let Value::Array(v) = self {
+            let mut v = v.unshared();
             v.push_back(other);
             return Value::Array(v);
-        } else if let Value::Array(mut v) = other {
+        } else if let Value::Array(v) = other {
+            let mut v = v.unshared();
             v.push_front(self);
             return Value::Array(v);
         }
         Value::Array(ArrayObject::from_vec(vec![self, other]))
     }
 
+    pub fn push(self, value: Value) -> Value {
+        match self {
+            Value::Array(ref v) => v.clone().push_back(value),
+            _ => limits::fail(format!("Push operation not available for {}", self.kind())),
+        }
+        self
+    }
+
+    pub fn pop(self) -> Value {
+        match self {
+            Value::Array(mut v) => {
+                if v.len() == 0 {
+                    limits::fail(format!("Pop from an empty Array."));
+                }
+                let idx = v.len() - 1;
+                v.remove(idx)
+            },
+            _ => limits::fail(format!("Pop operation not available for {}", self.kind())),
+        }
+    }
+
+    // An index equal to the length of the array, or -1, inserts at the end.
+    pub fn insert(self, index: Value, value: Value) -> Value {
+        match self {
+            Value::Array(ref v) => {
+                let i = index.as_int();
+                let len = v.len() as i64;
+                let idx = if i < 0 { i + len + 1 } else { i };
+                if idx < 0 || idx > len {
+                    limits::fail(format!("Index {} out of bounds for Array of length {}.", i, len));
+                }
+                v.clone().insert(idx as usize, value)
+            },
+            _ => limits::fail(format!("Insert operation not available for {}", self.kind())),
+        }
+        self
+    }
+
+    pub fn remove(self, index: Value) -> Value {
+        match self {
+            Value::Array(mut v) => {
+                let idx = resolve_index(index, v.len(), "Array");
+                v.remove(idx)
+            },
+            Value::Map(mut m) => m.remove(index),
+            _ => limits::fail(format!("Remove operation not available for {}", self.kind())),
+        }
+    }
+
+    pub fn reverse(self) -> Value {
+        match self {
+            Value::Array(ref v) => v.clone().reverse(),
+            _ => limits::fail(format!("Reverse operation not available for {}", self.kind())),
+        }
+        self
+    }
+
+    // The Ints from self up to but not including end, counting down if step
+    // is negative.
+    pub fn range(self, end: Value, step: Value) -> Value {
+        let (start, end, step) = (self.as_int(), end.as_int(), step.as_int());
+        if step == 0 {
+            limits::fail(format!("Range step must not be zero."));
+        }
+        let mut v = Vec::new();
+        let mut next = Some(start);
+        while let Some(i) = next {
+            if (step > 0 && i >= end) || (step < 0 && i <= end) {
+                break;
+            }
+            v.push(Value::Int(i));
+            // Stepping past the largest or smallest Int also ends the range.
+            next = i.checked_add(step);
+        }
+        Value::new_array(v)
+    }
+
+    // Pairs up the elements of two arrays, as far as the shorter goes.
+    pub fn zip(self, other: Value) -> Value {
+        let (a, b) = (self.as_array(), other.as_array());
+        let len = if a.len() < b.len() { a.len() } else { b.len() };
+        Value::new_array((0..len).map(|i| Value::new_array(vec![a.get(i), b.get(i)])).collect())
+    }
+
+    // Pairs each element of an array with its index.
+    pub fn enumerate(self) -> Value {
+        let a = self.as_array();
+        Value::new_array((0..a.len()).map(|i| Value::new_array(vec![Value::Int(i as i64), a.get(i)])).collect())
+    }
+
+    pub fn sort(self) -> Value {
+        match self {
+            Value::Array(ref v) => v.clone().sort(),
+            _ => limits::fail(format!("Sort operation not available for {}", self.kind())),
+        }
+        self
+    }
+
+    pub fn min(self) -> Value {
+        self.extreme(Ordering::Less, "min")
+    }
+
+    pub fn max(self) -> Value {
+        self.extreme(Ordering::Greater, "max")
+    }
+
+    // The first element of an array which no other comes before in the
+    // given direction.
+    fn extreme(self, ord: Ordering, name: &str) -> Value {
+        let a = self.as_array();
+        if a.len() == 0 {
+            limits::fail(format!("Cannot take the {} of an empty Array.", name));
+        }
+        let mut best = a.get(0);
+        for i in 1..a.len() {
+            let v = a.get(i);
+            if v.compare(&best) == ord {
+                best = v;
+            }
+        }
+        best
+    }
+
+    // Orders numbers and Bools, as for sorting.
+    pub fn compare(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (&Value::Int(a), &Value::Int(b)) => a.cmp(&b),
+            (&Value::Bool(a), &Value::Bool(b)) => a.cmp(&b),
+            (&Value::Int(_), &Value::Float(_)) |
+            (&Value::Float(_), &Value::Int(_)) |
+            (&Value::Float(_), &Value::Float(_)) => {
+                let (a, b) = (self.clone().as_float(), other.clone().as_float());
+                a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+            },
+            _ => limits::fail(format!("Cannot order {} and {}.", self.kind(), other.kind())),
+        }
+    }
+
+    pub fn copy(self) -> Value {
+        match self {
+            Value::Array(v) => Value::Array(v.copy()),
+            Value::Map(m) => Value::Map(m.copy()),
+            Value::Struct(r) => Value::Struct(r.copy()),
+            _ => limits::fail(format!("Copy operation not available for {}", self.kind())),
+        }
+    }
+
+    pub fn keys(self) -> Value {
+        match self {
+            Value::Map(m) => Value::new_array(m.keys()),
+            _ => limits::fail(format!("Keys operation not available for {}", self.kind())),
+        }
+    }
+
+    pub fn values(self) -> Value {
+        match self {
+            Value::Map(m) => Value::new_array(m.values()),
+            _ => limits::fail(format!("Values operation not available for {}", self.kind())),
+        }
+    }
+
+    pub fn has(self, key: Value) -> Value {
+        match self {
+            Value::Map(m) => Value::Bool(m.has(key)),
+            _ => limits::fail(format!("Has operation not available for {}", self.kind())),
+        }
+    }
+
+    // What foreach runs over: the keys of a map, or the elements of
+    // anything else. foreach starts from the last element, so the keys are
+    // reversed for it to visit them in order.
+    pub fn iter(self) -> Value {
+        match self {
+            Value::Map(m) => {
+                let mut keys = m.keys();
+                keys.reverse();
+                Value::new_array(keys)
+            },
+            _ => self,
+        }
+    }
+
+    // The parts of a tuple, or the elements of an array if arrays is set, to
+    // be bound to as many names.
+    pub fn unpack(self, n: usize, arrays: bool) -> Vec<Value> {
+        let parts = match self {
+            Value::Tuple(t) => (*t).clone(),
+            Value::Array(a) if arrays => (0..a.len()).map(|i| a.get(i)).collect(),
+            Value::Array(_) => limits::fail("Only tuples may be taken apart in reversible code.".to_string()),
+            _ => limits::fail(format!("Cannot unpack {}.", self.kind())),
+        };
+        if parts.len() != n {
+            limits::fail(format!("Cannot unpack {} values into {} names.", parts.len(), n));
+        }
+        parts
+    }
+
+    // Names the type of a value for error messages.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Value::Null => "Null",
+            Value::Addr(_) => "Addr",
+            Value::Int(_) => "Int",
+            Value::Bool(_) => "Bool",
+            Value::Float(_) => "Float",
+            Value::String(_) => "String",
+            Value::Func(_) | Value::Adjoint(_) => "Func",
+            Value::Array(_) => "Array",
+            Value::Tuple(_) => "Tuple",
+            Value::Map(_) => "Map",
+            Value::Struct(_) => "Struct",
+            Value::QuReg(_) => "QuReg",
+        }
+    }
+
     pub fn as_int(self) -> i64 {
         match self {
             Value::Int(v) => v,
             Value::Float(v) => v as i64,
             Value::Bool(v) => v as i64, 
-            _ => panic!("Invalid cast of {:?} to Int", self),
+            _ => limits::fail(format!("Invalid cast of {} to Int", self.kind())),
         }
     }
 
@@ -251,7 +616,7 @@ impl Value {
         match self {
             Value::Int(v) => v as f64,
             Value::Float(v) => v,
-            _ => panic!("Invalid cast of {:?} to Float", self),
+            _ => limits::fail(format!("Invalid cast of {} to Float", self.kind())),
         }
     }
 
@@ -259,28 +624,28 @@ impl Value {
         match self {
             Value::Bool(v) => v,
             Value::Int(v) => if v != 0 { true } else { false },
-            _ => panic!("Invalid cast of {:?} to Bool", self),
+            _ => limits::fail(format!("Invalid cast of {} to Bool", self.kind())),
         }
     }
 
-    pub fn as_func(self) -> FunctionToken {
+    pub fn as_addr(self) -> usize {
         match self {
-            Value::Func(ft) => ft,
-            _ => panic!("Invalid cast of {:?} to Func", self),
+            Value::Addr(v) => v,
+            _ => limits::fail(format!("Invalid cast of {} to Addr", self.kind())),
         }
     }
 
-    pub fn as_addr(self) -> usize {
+    pub fn as_array(self) -> ArrayObject {
         match self {
-            Value::Addr(v) => v,
-            _ => panic!("Invalid cast of {:?} to Addr", self),
+            Value::Array(v) => v,
+            _ => limits::fail(format!("Invalid cast of {} to Array", self.kind())),
         }
     }
 
     pub fn as_qureg(self) -> QuRegObject {
         match self {
             Value::QuReg(v) => v,
-            _ => panic!("Invalid cast of {:?} to QuReg", self),
+            _ => limits::fail(format!("Invalid cast of {} to QuReg", self.kind())),
         }
     }
 
@@ -289,9 +654,63 @@ impl Value {
             Value::Bool(v) => v.to_string(),
             Value::Int(v) => v.to_string(),
             Value::Float(v) => v.to_string(),
+            Value::String(v) => (*string_table::get(v)).clone(),
             Value::Array(v) => v.to_string(),
+            Value::Tuple(v) => {
+                let parts: Vec<String> = v.iter().map(|x| x.clone().as_string()).collect();
+                format!("({})", parts.join(", "))
+            },
+            Value::Map(v) => v.to_string(),
+            Value::Struct(v) => v.to_string(),
             Value::QuReg(v) => v.to_string(),
-            _ => panic!("String representation not available for {:?}", &self),
+            _ => limits::fail(format!("String representation not available for {}", self.kind())),
         }
     }
 }
+
+// Resolves an index, which counts back from the end if negative.
+fn resolve_index(index: Value, len: usize, kind: &str) -> usize {
+    let i = index.as_int();
+    let idx = if i < 0 { i + len as i64 } else { i };
+    if idx < 0 || idx >= len as i64 {
+        limits::fail(format!("Index {} out of bounds for {} of length {}.", i, kind, len));
+    }
+    idx as usize
+}
+
+// Resolves the bounds and step of a slice as Python does, into the index of
+// its first element, its length and its step. With a negative step, the
+// slice runs down from its first bound to just above its second.
+fn resolve_slice(index1: Value, index2: Value, step: Value, len: usize, kind: &str) -> (usize, usize, i64) {
+    let step = match step {
+        Value::Null => 1,
+        step => step.as_int(),
+    };
+    if step == 0 {
+        limits::fail(format!("Slice step must not be zero."));
+    }
+    let len = len as i64;
+    let bound = |b: Value, default: i64| match b {
+        Value::Null => default,
+        b => {
+            let i = b.as_int();
+            let idx = if i < 0 { i + len } else { i };
+            if idx < 0 || idx > len {
+                limits::fail(format!("Slice bound {} out of bounds for {} of length {}.", i, kind, len));
+            }
+            idx
+        },
+    };
+    let (first, last) = if step > 0 {
+        (bound(index1, 0), bound(index2, len))
+    } else {
+        (bound(index1, len - 1).min(len - 1), bound(index2, -1))
+    };
+    let n = (last - first + step - step.signum()) / step;
+    if n <= 0 {
+        (0, 0, step)
+    } else {
+        (first as usize, n as usize, step)
+    }
+}
+
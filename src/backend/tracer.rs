@@ -0,0 +1,169 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+// Writes one tab-separated line per event:
+//
+//   insn  <function>  <pc>  <stack depth>  <instruction>
+//   call  <function>  <builtin>  <regular|reverse|inverse>  <value>...
+//   gate  <function>  <gate>  <qubit>,<qubit>...  <parameter or ->
+//
+// where <function> is the innermost active Quick function, and qubits are the
+// raw indices handed to libquantum (which shift as scratch qubits come and go).
+
+use backend::bytecode::*;
+use backend::interpreter::{self, Limits, Monitor, State};
+use backend::runtime::IRT_STRINGS;
+use backend::runtime::observer::{self, GateObserver};
+use backend::runtime::value::Value;
+use util::string_table::{self, StringToken};
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+struct TraceLog {
+    out: Box<Write>,
+    function: String,
+    enabled: bool,
+}
+
+struct Tracer {
+    log: Rc<RefCell<TraceLog>>,
+    filter: Option<StringToken>,
+    frames: Vec<Option<StringToken>>,
+}
+
+struct GateTracer {
+    log: Rc<RefCell<TraceLog>>,
+}
+
+// Interprets the program, tracing it to `out`. If `filter` names a function,
+// only events while that function is active (including in anything it calls)
+// are written.
+pub fn trace(program: Program, out: Box<Write>, filter: Option<&str>, limits: &Limits) -> Result<(), String> {
+    let log = Rc::new(RefCell::new(TraceLog {
+        out: out,
+        function: "<top>".to_string(),
+        enabled: filter.is_none(),
+    }));
+    let mut tracer = Tracer {
+        log: log.clone(),
+        filter: filter.map(string_table::insert),
+        frames: vec![None],
+    };
+    observer::set_observer(Some(Box::new(GateTracer { log: log })));
+    let result = interpreter::interpret_with(program, limits, &mut tracer);
+    observer::set_observer(None);
+    let _ = tracer.log.borrow_mut().out.flush();
+    result
+}
+
+fn name(name: Option<StringToken>) -> String {
+    match name {
+        Some(name) => (*string_table::get(name)).clone(),
+        None => "<top>".to_string(),
+    }
+}
+
+fn kind(kind: Call) -> &'static str {
+    match kind {
+        Call::Regular => "regular",
+        Call::Reverse => "reverse",
+        Call::Inverse => "inverse",
+    }
+}
+
+fn value(program: &Program, v: &Value) -> String {
+    match *v {
+        Value::Null => "null".to_string(),
+        Value::Addr(a) => format!("@{}", a),
+        Value::Func(ft) => if ft.is_native() {
+            IRT_STRINGS[ft.to_native_index()].to_string()
+        } else {
+            name(program.call_table[ft.to_call_index()].name)
+        },
+        Value::Adjoint(ft) => format!("adjoint {}", value(program, &Value::Func(ft))),
+        Value::Array(ref a) => {
+            let elems: Vec<String> = (0..a.len()).map(|i| value(program, &a.get(i))).collect();
+            format!("[{}]", elems.join(" "))
+        },
+        Value::QuReg(ref q) => {
+            let qubits: Vec<String> = q.qubits().iter().map(|i| i.to_string()).collect();
+            format!("qureg({})", qubits.join(","))
+        },
+        _ => v.clone().as_string(),
+    }
+}
+
+impl Tracer {
+    fn update(&mut self) {
+        let mut log = self.log.borrow_mut();
+        log.function = name(*self.frames.last().unwrap());
+        log.enabled = match self.filter {
+            Some(f) => self.frames.contains(&Some(f)),
+            None => true,
+        };
+    }
+}
+
+impl Monitor for Tracer {
+    fn instruction(&mut self, state: &State) {
+        let mut log = self.log.borrow_mut();
+        if log.enabled {
+            let line = format!("insn\t{}\t{}\t{}\t{:?}", log.function, state.pc, state.stack.len(),
+                               state.program.instructions[state.pc]);
+            let _ = writeln!(log.out, "{}", line);
+        }
+    }
+
+    fn native(&mut self, ft: FunctionToken, k: Call, args: &[Value], state: &State) {
+        let mut log = self.log.borrow_mut();
+        if log.enabled {
+            let mut line = format!("call\t{}\t{}\t{}", log.function, IRT_STRINGS[ft.to_native_index()], kind(k));
+            for a in args.iter() {
+                line.push('\t');
+                line.push_str(&value(state.program, a));
+            }
+            let _ = writeln!(log.out, "{}", line);
+        }
+    }
+
+    fn enter(&mut self, ft: FunctionToken, state: &State) {
+        self.frames.push(state.program.call_table[ft.to_call_index()].name);
+        self.update();
+    }
+
+    fn leave(&mut self, _state: &State) {
+        let _ = self.frames.pop();
+        self.update();
+    }
+}
+
+impl GateObserver for GateTracer {
+    fn gate(&mut self, gate: &'static str, qubits: &[usize], param: Option<f64>) {
+        let mut log = self.log.borrow_mut();
+        if log.enabled {
+            let qubits: Vec<String> = qubits.iter().map(|i| i.to_string()).collect();
+            let param = match param {
+                Some(p) => p.to_string(),
+                None => "-".to_string(),
+            };
+            let line = format!("gate\t{}\t{}\t{}\t{}", log.function, gate, qubits.join(","), param);
+            let _ = writeln!(log.out, "{}", line);
+        }
+    }
+}
@@ -24,27 +24,61 @@ pub type Ast = LinkedList<Stmt>;
 
 #[derive(Debug)]
 pub enum Stmt {
-    DefFunc(StringToken, LinkedList<StringToken>, Expr),
-    DefRFunc(StringToken, LinkedList<StringToken>, Expr),
-    DefVar(StringToken, Expr),
+    DefFunc(StringToken, LinkedList<Pattern>, Expr),
+    DefRFunc(StringToken, LinkedList<Pattern>, Expr),
+    DefVar(Pattern, Expr),
+    DefConst(StringToken, Expr),
+    DefStruct(StringToken, LinkedList<StringToken>),
     Block(LinkedList<Stmt>),
     While(Expr, Box<Stmt>),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
-    ForEach(StringToken, Expr, Box<Stmt>),
+    ForEach(Pattern, Expr, Box<Stmt>),
     ForLoop(StringToken, Expr, Expr, Box<Stmt>),
     With(StringToken, Expr, Box<Stmt>),
+    Ctrl(Expr, Box<Stmt>),
+    QIf(Expr, Box<Stmt>),
     Expr(Expr),
     Return(Expr),
+    Break(Option<StringToken>),
+    Continue(Option<StringToken>),
+    Labeled(StringToken, Box<Stmt>),
     Print(StringToken, LinkedList<Expr>),
+    Import(StringToken, Option<StringToken>),
+    Located(usize, Box<Stmt>),
+}
+
+impl Stmt {
+    // Tags a statement with the position in the source it was parsed from.
+    pub fn at(pos: usize, stmt: Stmt) -> Stmt {
+        Stmt::Located(pos, Box::new(stmt))
+    }
+
+    // The statement itself, without its position.
+    pub fn unlocated(&self) -> &Stmt {
+        match *self {
+            Stmt::Located(_, ref s) => s.unlocated(),
+            _ => self,
+        }
+    }
+}
+
+// What a variable, loop variable or parameter is bound to: either a name, or
+// a tuple of patterns which takes apart a tuple or array.
+#[derive(Debug)]
+pub enum Pattern {
+    Name(StringToken),
+    Tuple(LinkedList<Pattern>),
 }
 
 pub type Bxpr = Box<Expr>;
 
 #[derive(Debug)]
 pub enum Expr {
+    Null,
     Int(i64),
     Float(f64),
     Bool(bool),
+    String(StringToken),
     Ref(StringToken),
     If(Bxpr, Bxpr, Bxpr),
     Block(LinkedList<Stmt>, Bxpr),
@@ -52,14 +86,19 @@ pub enum Expr {
     Call(Bxpr, LinkedList<Expr>),
     Assign(StringToken, Bxpr),
     Array(LinkedList<Expr>),
+    Tuple(LinkedList<Expr>),
+    Map(LinkedList<(Expr, Expr)>),
+    Struct(StringToken, LinkedList<(StringToken, Expr)>),
     UnOp(UnOp, Bxpr),
     BinOp(Bxpr, BinOp, Bxpr),
     Cat(Bxpr, Bxpr),
     Get(Bxpr, Bxpr),
     Put(Bxpr, Bxpr, Bxpr),
-    Slice(Bxpr, Bxpr, Bxpr),
+    Slice(Bxpr, Bxpr, Bxpr, Bxpr),
     Len(Bxpr),
     QAlloc(Bxpr, Bxpr),
     Apply(Bxpr, Bxpr),
     Invoke(Bxpr),
+    Adjoint(Bxpr),
+    Member(Bxpr, StringToken),
 }
@@ -17,3 +17,5 @@
 
 pub mod ast;
 pub mod parser;
+pub mod prelude;
+pub mod modules;
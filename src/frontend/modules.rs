@@ -0,0 +1,128 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use frontend::ast::*;
+use frontend::parser::Rdp;
+use util::string_table::{self, StringToken};
+
+use pest::prelude::*;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::vec::Vec;
+
+// A parsed source file, along with the modules it imports, as indices into
+// the list returned by load under the names they were imported as.
+pub struct Module {
+    pub name: String,
+    pub ast: Ast,
+    pub imports: HashMap<StringToken, usize>,
+}
+
+struct Loader<'a> {
+    search: &'a [PathBuf],
+    modules: Vec<Module>,
+    loaded: HashMap<PathBuf, usize>,
+    loading: Vec<PathBuf>,
+}
+
+// Loads every module the program imports, directly or not. Imports are found
+// relative to the importing file's directory first, then in each directory
+// of the search path. The modules come back in an order in which each comes
+// after those it imports, with the program itself last.
+pub fn load(name: &str, ast: Ast, dir: &Path, search: &[PathBuf]) -> Result<Vec<Module>, String> {
+    let mut loader = Loader {
+        search: search,
+        modules: Vec::new(),
+        loaded: HashMap::new(),
+        loading: Vec::new(),
+    };
+    let imports = loader.imports(&ast, dir)?;
+    let mut modules = loader.modules;
+    modules.push(Module { name: name.to_string(), ast: ast, imports: imports });
+    Ok(modules)
+}
+
+impl<'a> Loader<'a> {
+    fn imports(&mut self, ast: &Ast, dir: &Path) -> Result<HashMap<StringToken, usize>, String> {
+        let mut imports = HashMap::new();
+        for stmt in ast.iter() {
+            if let Stmt::Import(path, alias) = *stmt.unlocated() {
+                let path = string_table::get(path);
+                let alias = match alias {
+                    Some(alias) => alias,
+                    None => default_name(&path)?,
+                };
+                let m = self.module(&path, dir)?;
+                if imports.insert(alias, m).is_some() {
+                    return Err(format!("A module named '{}' is already imported", string_table::get(alias)));
+                }
+            }
+        }
+        Ok(imports)
+    }
+
+    fn module(&mut self, path: &str, dir: &Path) -> Result<usize, String> {
+        let file = self.find(path, dir)?;
+        if let Some(&m) = self.loaded.get(&file) {
+            return Ok(m);
+        }
+        if self.loading.contains(&file) {
+            return Err(format!("Circular import of module '{}'", path));
+        }
+        let mut source = String::new();
+        if let Err(err) = File::open(&file).and_then(|mut f| f.read_to_string(&mut source)) {
+            return Err(format!("Failed to read module '{}': {}", path, err));
+        }
+        let mut parser = Rdp::new(StringInput::new(source.as_str()));
+        if !parser.program() || !parser.end() {
+            let (_, pos) = parser.expected();
+            let line = source[..pos].matches('\n').count() + 1;
+            return Err(format!("Failed to parse module '{}' at line {}", path, line));
+        }
+        let ast = parser.parse();
+        self.loading.push(file.clone());
+        let imports = self.imports(&ast, file.parent().unwrap())?;
+        let _ = self.loading.pop();
+        self.modules.push(Module { name: path.to_string(), ast: ast, imports: imports });
+        let m = self.modules.len() - 1;
+        self.loaded.insert(file, m);
+        Ok(m)
+    }
+
+    fn find(&self, path: &str, dir: &Path) -> Result<PathBuf, String> {
+        for d in Some(dir).into_iter().chain(self.search.iter().map(|d| d.as_path())) {
+            let file = d.join(path);
+            if file.is_file() {
+                return file.canonicalize().map_err(|err| format!("Failed to read module '{}': {}", path, err));
+            }
+        }
+        Err(format!("Module '{}' not found", path))
+    }
+}
+
+// Names a module after its file, as in "lib/grover.qk" becoming "grover".
+fn default_name(path: &str) -> Result<StringToken, String> {
+    let stem = Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let valid = stem.chars().enumerate().all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    if stem.is_empty() || !valid {
+        return Err(format!("Module '{}' needs a name, as in: import \"{}\" as name;", path, path));
+    }
+    Ok(string_table::insert(stem))
+}
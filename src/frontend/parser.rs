@@ -39,27 +39,43 @@ impl_rdp! {
             if_stmt |
             while_stmt |
             var_stmt |
+            const_stmt |
+            struct_stmt |
             print_stmt |
             block_stmt |
             ret_stmt |
             with_stmt |
+            ctrl_stmt |
+            qif_stmt |
+            import_stmt |
+            break_stmt |
+            cont_stmt |
+            label_stmt |
             expr_stmt
         }
 
         // Types of statements
-        rfunc_stmt = { ["func"] ~ ["*"] ~ iden ~ iden_list ~ (expr ~ [";"] | block_expr) }
-        func_stmt  = { ["func"] ~ iden ~ iden_list ~ (expr ~ [";"] | block_expr) }
-        var_stmt   = { ["var"] ~ iden ~ ["="] ~ expr ~ [";"] }
+        rfunc_stmt = { ["func"] ~ ["*"] ~ iden ~ param_list ~ (expr ~ [";"] | block_expr) }
+        func_stmt  = { ["func"] ~ iden ~ param_list ~ (expr ~ [";"] | block_expr) }
+        var_stmt   = { ["var"] ~ pattern ~ ["="] ~ expr ~ [";"] }
+        const_stmt = { ["const"] ~ iden ~ ["="] ~ expr ~ [";"] }
+        struct_stmt = { ["struct"] ~ iden ~ blk_s ~ (iden ~ ([","] ~ iden)*)? ~ blk_e }
         block_stmt = { blk_s ~ stmt* ~ blk_e }
         while_stmt = { ["while"] ~ ["("] ~ expr ~ [")"] ~ stmt }
         ife_stmt   = { ["if"] ~ ["("] ~ expr ~ [")"] ~ stmt ~ ["else"] ~ stmt }
         if_stmt    = { ["if"] ~ ["("] ~ expr ~ [")"] ~ stmt }
-        fore_stmt  = { ["foreach"] ~ ["("] ~ iden ~ ["in"] ~ expr ~ [")"] ~ stmt }
+        fore_stmt  = { ["foreach"] ~ ["("] ~ pattern ~ ["in"] ~ expr ~ [")"] ~ stmt }
         forl_stmt  = { ["for"] ~ ["("] ~ iden ~ ["in"] ~ expr ~ [".."] ~ expr ~ [")"] ~ stmt }
         expr_stmt  = { expr ~ [";"] }
         with_stmt  = { ["with"] ~ ["("] ~ iden ~ ["="] ~ expr ~ [")"] ~ stmt }
+        ctrl_stmt  = { ["ctrl"] ~ ["("] ~ expr ~ [")"] ~ stmt }
+        qif_stmt   = { ["qif"] ~ ["("] ~ expr ~ [")"] ~ stmt }
         print_stmt = { ["print"] ~ lst_s ~ string ~ ([","] ~ arg)* ~ lst_e ~ [";"] }
         ret_stmt   = { ["ret"] ~ expr ~ [";"] }
+        import_stmt = { ["import"] ~ string ~ (["as"] ~ iden)? ~ [";"] }
+        break_stmt = { ["break"] ~ iden? ~ [";"] }
+        cont_stmt  = { ["continue"] ~ iden? ~ [";"] }
+        label_stmt = { iden ~ [":"] ~ (while_stmt | fore_stmt | forl_stmt) }
 
         // Most everything else is an expression
         expr = _{
@@ -82,15 +98,20 @@ impl_rdp! {
             call_expr |
             move_expr |
             alloc_expr |
+            tuple_expr |
+            struct_expr |
+            map_expr |
             block_expr |
             array_expr |
             assign_expr |
             put_expr |
             slice_expr |
-            get_expr
+            get_expr |
+            field_expr |
+            member_expr
         }
 
-        lit   = _{ float | bnum | hnum | snum | blit }
+        lit   = _{ float | bnum | hnum | snum | blit | string }
 
         // Operators for matching later
         plus  =  { ["+"] }
@@ -115,12 +136,14 @@ impl_rdp! {
         cat   =  { ["><"] }
         len   =  { ["#"] }
         apply =  { ["$"] }
+        adjoint = { ["adjoint"] }
         blk_s =  { ["{"] }
         blk_e =  { ["}"] }
         lst_s =  { ["("] }
         lst_e =  { [")"] }
         arr_s =  { ["["] }
         arr_e =  { ["]"] }
+        colon =  { [":"] }
 
         // Expressions to match
         if_expr     = { ["if"] ~ ["("] ~ expr ~ [")"] ~ expr ~ ["else"] ~ expr }
@@ -129,17 +152,26 @@ impl_rdp! {
         assign_expr = { iden ~ ["="] ~ expr }
         get_expr    = { caller ~ ["["] ~ expr ~ ["]"] }
         put_expr    = { caller ~ ["["] ~ expr ~ ["]"] ~ ["="] ~ expr }
-        slice_expr  = { caller ~ ["["] ~ expr ~ [":"] ~ expr ~ ["]"] }
+        slice_expr  = { caller ~ ["["] ~ expr? ~ colon ~ expr? ~ (colon ~ expr?)? ~ arr_e }
         array_expr  = { arr_s ~ (arg ~ ([","] ~ arg)*)? ~ arr_e } 
+        tuple_expr  = { lst_s ~ arg ~ ([","] ~ arg)+ ~ lst_e }
+        map_expr    = { blk_s ~ (entry ~ ([","] ~ entry)*)? ~ blk_e }
+        struct_expr = { iden ~ blk_s ~ (field ~ ([","] ~ field)*)? ~ blk_e }
         alloc_expr  = { ["|"] ~ expr ~ [","] ~ expr ~ [">"] }
-        unary_expr  = { (apply | not | bnot | minus | len) ~ rexpr }
+        unary_expr  = { (apply | not | bnot | minus | len | adjoint) ~ rexpr }
         move_expr   = { ["`"] ~ iden }
+        member_expr = { iden ~ member+ }
+        member      = { ["."] ~ iden }
+        field_expr  = { member_expr ~ ["="] ~ expr }
+        entry       = { expr ~ [":"] ~ expr }
+        field       = { iden ~ [":"] ~ expr }
 
         // Helper rules
         arg       = { expr }
-        iden_list = _{ ["("] ~ (iden ~ ([","] ~ iden)*)? ~ [")"] }
+        pattern   = { iden | lst_s ~ pattern ~ ([","] ~ pattern)+ ~ lst_e }
+        param_list = _{ ["("] ~ (pattern ~ ([","] ~ pattern)*)? ~ [")"] }
         arg_list  = _{ lst_s ~ (arg ~ ([","] ~ arg)*)? ~ lst_e }
-        caller    = _{ array_expr | block_expr | move_expr | ["("] ~ expr ~ [")"] | lit | iden }
+        caller    = _{ array_expr | map_expr | block_expr | move_expr | tuple_expr | ["("] ~ expr ~ [")"] | lit | member_expr | iden }
 
         // Literals and identifiers
         iden   = @{ (['a'..'z'] | ['A'..'Z'] | ["_"] ) ~ (['a'..'z'] | ['A'..'Z'] | ["_"] | ['0'..'9'])* } 
@@ -173,47 +205,89 @@ impl_rdp! {
             () => LinkedList::new()
         }
         _stmt(&self) -> Stmt {
-            (_: rfunc_stmt, &name: iden, params: _iden_list(), body: _expr()) => {
+            (_: rfunc_stmt, &name: iden, params: _pattern_list(), body: _expr()) => {
                 Stmt::DefRFunc(string_table::insert(name), params, body)
             },
-            (_: func_stmt, &name: iden, params: _iden_list(), body: _expr()) => {
+            (_: func_stmt, &name: iden, params: _pattern_list(), body: _expr()) => {
                 Stmt::DefFunc(string_table::insert(name), params, body)
             },
-            (_: var_stmt, &i: iden, e: _expr()) => Stmt::DefVar(string_table::insert(i), e),
+            (_: var_stmt, _: pattern, p: _pattern(), e: _expr()) => Stmt::DefVar(p, e),
+            (_: const_stmt, &i: iden, e: _expr()) => Stmt::DefConst(string_table::insert(i), e),
+            (_: struct_stmt, &name: iden, _: blk_s, fields: _iden_list(), _: blk_e) => {
+                Stmt::DefStruct(string_table::insert(name), fields)
+            },
             (_: block_stmt, _: blk_s, stmts: _stmt_list(), _: blk_e) => {
                 Stmt::Block(stmts)
             },
-            (_: while_stmt, pred: _expr(), _: stmt, body: _stmt()) => Stmt::While(pred, Box::new(body)),
-            (_: if_stmt, pred: _expr(), _: stmt, body: _stmt()) => Stmt::If(pred, Box::new(body), None),
-            (_: ife_stmt, pred: _expr(), _: stmt, body: _stmt(), _: stmt, other: _stmt()) => {
-                Stmt::If(pred, Box::new(body), Some(Box::new(other)))
+            (_: while_stmt, pred: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::While(pred, Box::new(Stmt::at(s.start, body)))
+            },
+            (_: if_stmt, pred: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::If(pred, Box::new(Stmt::at(s.start, body)), None)
+            },
+            (_: ife_stmt, pred: _expr(), s1: stmt, body: _stmt(), s2: stmt, other: _stmt()) => {
+                Stmt::If(pred, Box::new(Stmt::at(s1.start, body)), Some(Box::new(Stmt::at(s2.start, other))))
             },
-            (_: fore_stmt, &name: iden, iter: _expr(), _: stmt, body: _stmt()) => {
-                Stmt::ForEach(string_table::insert(name), iter, Box::new(body))
+            (_: fore_stmt, _: pattern, p: _pattern(), iter: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::ForEach(p, iter, Box::new(Stmt::at(s.start, body)))
             },
-            (_: forl_stmt, &name: iden, start: _expr(), end: _expr(), _: stmt, body: _stmt()) => {
-                Stmt::ForLoop(string_table::insert(name), start, end, Box::new(body))
+            (_: forl_stmt, &name: iden, start: _expr(), end: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::ForLoop(string_table::insert(name), start, end, Box::new(Stmt::at(s.start, body)))
             },
             (_: ret_stmt, value: _expr()) => Stmt::Return(value),
-            (_: with_stmt, &name:iden, pred: _expr(), _: stmt, body: _stmt()) => {
-                Stmt::With(string_table::insert(name), pred, Box::new(body))
+            (_: with_stmt, &name:iden, pred: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::With(string_table::insert(name), pred, Box::new(Stmt::at(s.start, body)))
+            },
+            (_: ctrl_stmt, control: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::Ctrl(control, Box::new(Stmt::at(s.start, body)))
+            },
+            (_: qif_stmt, control: _expr(), s: stmt, body: _stmt()) => {
+                Stmt::QIf(control, Box::new(Stmt::at(s.start, body)))
             },
+            (_: import_stmt, &path: string, &name: iden) => {
+                let path_len = path.len();
+                Stmt::Import(string_table::insert(&path[1..path_len-1]), Some(string_table::insert(name)))
+            },
+            (_: import_stmt, &path: string) => {
+                let path_len = path.len();
+                Stmt::Import(string_table::insert(&path[1..path_len-1]), None)
+            },
+            (_: break_stmt, &label: iden) => Stmt::Break(Some(string_table::insert(label))),
+            (_: break_stmt) => Stmt::Break(None),
+            (_: cont_stmt, &label: iden) => Stmt::Continue(Some(string_table::insert(label))),
+            (_: cont_stmt) => Stmt::Continue(None),
+            (_: label_stmt, &label: iden, body: _stmt()) => Stmt::Labeled(string_table::insert(label), Box::new(body)),
             (_: expr_stmt, e: _expr()) => Stmt::Expr(e),
             (_: print_stmt, _: lst_s, &s: string, args: _arg_list(), _: lst_e) => {
                 let s_len = s.len();
                 Stmt::Print(string_table::insert(&s[1..s_len-1]), args) 
             },
         }
-        _stmt_list(&self) -> LinkedList<Stmt> {
-            (_: stmt, head: _stmt(), mut rest: _stmt_list()) => {
+        _pattern(&self) -> Pattern {
+            (&i: iden) => Pattern::Name(string_table::insert(i)),
+            (_: lst_s, patterns: _pattern_list(), _: lst_e) => Pattern::Tuple(patterns),
+        }
+        _pattern_list(&self) -> LinkedList<Pattern> {
+            (_: pattern, head: _pattern(), mut rest: _pattern_list()) => {
                 rest.push_front(head);
                 rest
             },
             () => LinkedList::new()
         }
+        _stmt_list(&self) -> LinkedList<Stmt> {
+            (s: stmt, head: _stmt(), mut rest: _stmt_list()) => {
+                rest.push_front(Stmt::at(s.start, head));
+                rest
+            },
+            () => LinkedList::new()
+        }
         _expr(&self) -> Expr {
             (&i: iden) => Expr::Ref(string_table::insert(i)),
             (&blit: blit) => Expr::Bool(blit.parse::<bool>().unwrap()),
+            (&s: string) => {
+                let s_len = s.len();
+                Expr::String(string_table::insert(&s[1..s_len-1]))
+            },
             (&num: snum) => Expr::Int(num.parse::<i64>().unwrap()),
             (&num: bnum) => Expr::Int(i64::from_str_radix(&num[2..], 2).unwrap()),
             (&num: hnum) => Expr::Int(i64::from_str_radix(&num[2..], 16).unwrap()),
@@ -237,14 +311,27 @@ impl_rdp! {
             (_: move_expr, &i: iden) => {
                 Expr::Move(string_table::insert(i))
             },
+            (_: member_expr, &i: iden, names: _member_list()) => {
+                names.into_iter().fold(Expr::Ref(string_table::insert(i)), |e, name| {
+                    Expr::Member(Box::new(e), name)
+                })
+            },
+            // Setting a field puts to the struct under the field's name
+            (_: field_expr, target: _expr(), value: _expr()) => match target {
+                Expr::Member(s, name) => Expr::Put(s, Box::new(Expr::String(name)), Box::new(value)),
+                _ => unreachable!(),
+            },
             (_: assign_expr, &var: iden, value: _expr()) => {
                 Expr::Assign(string_table::insert(var), Box::new(value))
             },
             (_: get_expr, a: _expr(), index: _expr()) => {
                 Expr::Get(Box::new(a), Box::new(index))
             },
-            (_: slice_expr, a: _expr(), index1: _expr(), index2: _expr()) => {
-                Expr::Slice(Box::new(a), Box::new(index1), Box::new(index2))
+            (_: slice_expr, a: _expr(), mut parts: _slice_parts()) => {
+                let index1 = parts.pop_front().unwrap();
+                let index2 = parts.pop_front().unwrap();
+                let step = parts.pop_front().unwrap_or(Expr::Null);
+                Expr::Slice(Box::new(a), Box::new(index1), Box::new(index2), Box::new(step))
             },
             (_: put_expr, a: _expr(), index: _expr(), value: _expr()) => {
                 Expr::Put(Box::new(a), Box::new(index), Box::new(value))
@@ -252,11 +339,22 @@ impl_rdp! {
             (_: array_expr, _: arr_s, args: _arg_list(), _: arr_e) => {
                 Expr::Array(args)
             },
+            (_: tuple_expr, _: lst_s, args: _arg_list(), _: lst_e) => {
+                Expr::Tuple(args)
+            },
+            (_: map_expr, _: blk_s, entries: _entry_list(), _: blk_e) => {
+                Expr::Map(entries)
+            },
+            (_: struct_expr, &name: iden, _: blk_s, fields: _field_list(), _: blk_e) => {
+                Expr::Struct(string_table::insert(name), fields)
+            },
             (_: unary_expr, op, e: _expr()) => {
                 if let Rule::apply = op.rule {
                     Expr::Invoke(Box::new(e))
                 } else if let Rule::len = op.rule {
                     Expr::Len(Box::new(e))
+                } else if let Rule::adjoint = op.rule {
+                    Expr::Adjoint(Box::new(e))
                 } else {
                     let unop = match op.rule {
                         Rule::minus => UnOp::Neg,
@@ -321,6 +419,47 @@ impl_rdp! {
                 }, Box::new(e2))
             },
         }
+        _member_list(&self) -> LinkedList<StringToken> {
+            (_: member, &name: iden, mut rest: _member_list()) => {
+                rest.push_front(string_table::insert(name));
+                rest
+            },
+            () => LinkedList::new()
+        }
+        // The bounds and step of a slice, any of which may be left out
+        _slice_parts(&self) -> LinkedList<Expr> {
+            (_: arr_e) => {
+                let mut parts = LinkedList::new();
+                parts.push_front(Expr::Null);
+                parts
+            },
+            (_: colon, mut rest: _slice_parts()) => {
+                rest.push_front(Expr::Null);
+                rest
+            },
+            (part: _expr(), mut rest: _slice_end()) => {
+                rest.push_front(part);
+                rest
+            }
+        }
+        _slice_end(&self) -> LinkedList<Expr> {
+            (_: arr_e) => LinkedList::new(),
+            (_: colon, rest: _slice_parts()) => rest
+        }
+        _entry_list(&self) -> LinkedList<(Expr, Expr)> {
+            (_: entry, key: _expr(), value: _expr(), mut rest: _entry_list()) => {
+                rest.push_front((key, value));
+                rest
+            },
+            () => LinkedList::new()
+        }
+        _field_list(&self) -> LinkedList<(StringToken, Expr)> {
+            (_: field, &name: iden, value: _expr(), mut rest: _field_list()) => {
+                rest.push_front((string_table::insert(name), value));
+                rest
+            },
+            () => LinkedList::new()
+        }
         _arg_list(&self) -> LinkedList<Expr> {
             (_: arg, head: _expr(), mut rest: _arg_list()) => {
                 rest.push_front(head);
@@ -0,0 +1,32 @@
+// Copyright (C) 2017 Michael Anthony Knyszek
+//
+// This file is part of QScript
+//
+// QScript is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// QScript is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use frontend::ast::Ast;
+use frontend::parser::Rdp;
+
+use pest::prelude::*;
+
+const SOURCE: &'static str = include_str!("prelude.qk");
+
+// Parses the prelude, which is built into the interpreter.
+pub fn parse() -> Ast {
+    let mut parser = Rdp::new(StringInput::new(SOURCE));
+    if !parser.program() || !parser.end() {
+        panic!("Internal Error: Failed to parse the prelude!");
+    }
+    parser.parse()
+}
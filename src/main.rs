@@ -26,7 +26,159 @@ mod backend;
 
 use pest::prelude::*;
 
-use std::io::{self, Read};
+use std::collections::LinkedList;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use backend::interpreter::Limits;
+
+const USAGE: &'static str = "\
+Usage: quick [run] [options] [file]
+       quick debug <file>
+
+Runs a Quick program read from the given file, or from stdin if none is given.
+The debugger reads its commands from stdin, so it requires a file.
+
+Options:
+  --no-opt           Disable the bytecode optimizer
+  --no-prelude       Don't load the functions of the standard prelude
+  --path=DIR         Look for imported modules in DIR, after the importing
+                     file's own directory and before those in QUICK_PATH
+  --trace[=FILE]     Log executed instructions, builtin calls and gates to
+                     FILE, or to stderr
+  --trace-fn=NAME    Only trace while the function NAME is active
+  --profile[=FILE]   Report time spent per function and builtin on stderr,
+                     and write folded call stacks for flamegraphs to FILE
+  --max-instructions=N
+                     Stop after executing N instructions
+  --max-depth=N      Stop when more than N function calls are active
+  --max-qubits=N     Stop when more than N qubits are allocated at once,
+                     counting scratch qubits
+  --max-stack=N      Stop when the stack holds more than N values";
+
+struct Options {
+    debug: bool,
+    optimize: bool,
+    prelude: bool,
+    paths: Vec<PathBuf>,
+    trace: bool,
+    trace_file: Option<String>,
+    trace_fn: Option<String>,
+    profile: bool,
+    profile_file: Option<String>,
+    limits: Limits,
+    file: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = env::args().skip(1).peekable();
+    let mut options = Options {
+        debug: false,
+        optimize: true,
+        prelude: true,
+        paths: Vec::new(),
+        trace: false,
+        trace_file: None,
+        trace_fn: None,
+        profile: false,
+        profile_file: None,
+        limits: Limits::default(),
+        file: None,
+    };
+    let command = args.peek().cloned();
+    match command.as_ref().map(|c| c.as_str()) {
+        Some("run") => { let _ = args.next(); },
+        Some("debug") => {
+            let _ = args.next();
+            options.debug = true;
+        },
+        _ => (),
+    }
+    for arg in args {
+        match arg.as_str() {
+            "--no-opt" => options.optimize = false,
+            "--no-prelude" => options.prelude = false,
+            _ if arg.starts_with("--path=") => options.paths.push(PathBuf::from(&arg["--path=".len()..])),
+            "--trace" => options.trace = true,
+            _ if arg.starts_with("--trace=") => {
+                options.trace = true;
+                options.trace_file = Some(arg["--trace=".len()..].to_string());
+            },
+            _ if arg.starts_with("--trace-fn=") => {
+                options.trace = true;
+                options.trace_fn = Some(arg["--trace-fn=".len()..].to_string());
+            },
+            "--profile" => options.profile = true,
+            _ if arg.starts_with("--profile=") => {
+                options.profile = true;
+                options.profile_file = Some(arg["--profile=".len()..].to_string());
+            },
+            _ if arg.starts_with("--max-instructions=") => {
+                options.limits.instructions = Some(parse_limit(&arg, "--max-instructions=")?);
+            },
+            _ if arg.starts_with("--max-depth=") => {
+                options.limits.depth = Some(parse_limit(&arg, "--max-depth=")?);
+            },
+            _ if arg.starts_with("--max-qubits=") => {
+                options.limits.qubits = Some(parse_limit(&arg, "--max-qubits=")?);
+            },
+            _ if arg.starts_with("--max-stack=") => {
+                options.limits.stack = Some(parse_limit(&arg, "--max-stack=")?);
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ if options.file.is_none() => options.file = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    if options.debug && options.file.is_none() {
+        return Err("No file given to debug".to_string());
+    }
+    if options.debug && (options.trace || options.profile) {
+        return Err("Tracing and profiling are not available in the debugger".to_string());
+    }
+    if options.trace && options.profile {
+        return Err("Tracing and profiling may not be combined".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_limit<T: FromStr>(arg: &str, prefix: &str) -> Result<T, String> {
+    arg[prefix.len()..].parse().map_err(|_| format!("Invalid limit in '{}'", arg))
+}
+
+fn trace_output(file: &Option<String>) -> io::Result<Box<Write>> {
+    match *file {
+        Some(ref path) => Ok(Box::new(BufWriter::new(File::create(path)?))),
+        None => Ok(Box::new(io::stderr())),
+    }
+}
+
+fn read_source(file: &Option<String>, buffer: &mut String) -> io::Result<usize> {
+    match *file {
+        Some(ref path) => File::open(path)?.read_to_string(buffer),
+        None => io::stdin().read_to_string(buffer),
+    }
+}
+
+// Loads the modules the program imports, searching the directories given with
+// --path and then those in QUICK_PATH.
+fn load_modules(options: &Options, ast: frontend::ast::Ast) -> Result<Vec<frontend::modules::Module>, String> {
+    let mut search = options.paths.clone();
+    if let Some(paths) = env::var_os("QUICK_PATH") {
+        search.extend(env::split_paths(&paths));
+    }
+    match options.file {
+        Some(ref path) => {
+            let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+            frontend::modules::load(path, ast, dir, &search)
+        },
+        None => frontend::modules::load("<stdin>", ast, Path::new("."), &search),
+    }
+}
 
 fn print_line_from_pos(program: &String, pos: usize) {
     let mut line_start = pos;
@@ -43,9 +195,19 @@ fn print_line_from_pos(program: &String, pos: usize) {
 }
 
 fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(err) => {
+            println!("[Error] {}\n{}", err, USAGE);
+            return;
+        },
+    };
     libquantum::reseed();
     let mut buffer = String::new();
-    let _ = io::stdin().read_to_string(&mut buffer).unwrap();
+    if let Err(err) = read_source(&options.file, &mut buffer) {
+        println!("[Error] Failed to read program: {}", err);
+        return;
+    }
     let mut parser = frontend::parser::Rdp::new(StringInput::new(buffer.as_str()));
     if !parser.program() || !parser.end() {
         let (rules, pos) = parser.expected();
@@ -58,8 +220,46 @@ fn main() {
         return;
     }
     let ast = parser.parse();
-    match backend::compiler::compile(&ast) {
-        Ok(program) => backend::interpreter::interpret(program),
+    let modules = match load_modules(&options, ast) {
+        Ok(modules) => modules,
+        Err(err) => {
+            println!("[Import Error] {}", err);
+            return;
+        },
+    };
+    let prelude = if options.prelude { frontend::prelude::parse() } else { LinkedList::new() };
+    match backend::compiler::compile(&prelude, &modules, options.optimize && !options.debug) {
+        Ok(program) => {
+            let limits = &options.limits;
+            let result = if options.debug {
+                backend::debugger::debug(program, &buffer, limits)
+            } else if options.trace {
+                match trace_output(&options.trace_file) {
+                    Ok(out) => backend::tracer::trace(program, out, options.trace_fn.as_ref().map(|f| f.as_str()), limits),
+                    Err(err) => {
+                        println!("[Error] Failed to open trace file: {}", err);
+                        return;
+                    },
+                }
+            } else if options.profile {
+                match options.profile_file {
+                    Some(ref path) => match File::create(path) {
+                        Ok(f) => backend::profiler::profile(program, Some(Box::new(BufWriter::new(f))), limits),
+                        Err(err) => {
+                            println!("[Error] Failed to open profile file: {}", err);
+                            return;
+                        },
+                    },
+                    None => backend::profiler::profile(program, None, limits),
+                }
+            } else {
+                backend::interpreter::interpret(program, limits)
+            };
+            if let Err(err) = result {
+                println!("[Runtime Error] {}", err);
+                process::exit(1);
+            }
+        },
         Err(err) => println!("[Compile Error] {}", err),
     }
 }